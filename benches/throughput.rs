@@ -0,0 +1,16 @@
+use huffmancodes::bench::{self, Report};
+
+// Passing a number after `--` overrides the iterations per sample, e.g.
+// `cargo bench -- 10`.
+fn main() {
+    let iterations = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(5);
+    println!("{}", Report::header());
+    for (name, data) in bench::corpus() {
+        for report in bench::run_all(name, &data, iterations) {
+            println!("{}", report);
+        }
+    }
+}
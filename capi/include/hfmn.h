@@ -0,0 +1,154 @@
+#ifndef HFMN_H
+#define HFMN_H
+
+/* Generated by cbindgen from src/lib.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ Result of every call that can fail.
+ */
+typedef enum HfmnStatus {
+  HFMN_STATUS_OK = 0,
+  /*
+   A required pointer was null.
+   */
+  HFMN_STATUS_NULL_POINTER = 1,
+  /*
+   The input is not valid compressed data or a valid model.
+   */
+  HFMN_STATUS_INVALID_DATA = 2,
+  /*
+   The input holds a byte the model cannot code.
+   */
+  HFMN_STATUS_INVALID_INPUT = 3,
+  /*
+   Any other failure, including a panic caught at the boundary.
+   */
+  HFMN_STATUS_INTERNAL = 4,
+} HfmnStatus;
+
+/*
+ A loaded Huffman model. It is immutable, so one model can be used from
+ several threads at once.
+ */
+typedef struct HfmnModel HfmnModel;
+
+/*
+ Bytes allocated by the library. Release with `hfmn_buffer_free`.
+ */
+typedef struct HfmnBuffer {
+  uint8_t *data;
+  size_t len;
+} HfmnBuffer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Compresses `len` bytes at `data` into a self-contained stream, the same
+ layout `hfmn` writes to files.
+
+ # Safety
+
+ `data` must point to `len` readable bytes (or may be null when `len` is
+ 0) and `out` to writable memory for an `HfmnBuffer`.
+ */
+enum HfmnStatus hfmn_compress(const uint8_t *data, size_t len, struct HfmnBuffer *out);
+
+/*
+ Decompresses a stream from `hfmn_compress`. Fails with
+ `HFMN_STATUS_INVALID_DATA` rather than produce more than `max_len` bytes;
+ pass `SIZE_MAX` to trust the input.
+
+ # Safety
+
+ `data` must point to `len` readable bytes (or may be null when `len` is
+ 0) and `out` to writable memory for an `HfmnBuffer`.
+ */
+enum HfmnStatus hfmn_decompress(const uint8_t *data,
+                                size_t len,
+                                size_t max_len,
+                                struct HfmnBuffer *out);
+
+/*
+ Frees a buffer returned by the library and resets it to empty. Freeing
+ an empty buffer does nothing.
+
+ # Safety
+
+ `buffer` must be null or point to an `HfmnBuffer` filled in by this
+ library and not freed since.
+ */
+void hfmn_buffer_free(struct HfmnBuffer *buffer);
+
+/*
+ Loads a model saved with `HuffmanModel::to_bytes`.
+
+ # Safety
+
+ `bytes` must point to `len` readable bytes and `out` to writable memory
+ for a pointer.
+ */
+enum HfmnStatus hfmn_model_load(const uint8_t *bytes, size_t len, struct HfmnModel **out);
+
+/*
+ Frees a model from `hfmn_model_load`. Null is ignored.
+
+ # Safety
+
+ `model` must be null or a model from `hfmn_model_load` that has not been
+ freed yet.
+ */
+void hfmn_model_free(struct HfmnModel *model);
+
+/*
+ Huffman codes `len` bytes with `model`, escaping bytes it has no code
+ for when it can. The output holds only the bits, so the caller keeps
+ `len` to decode them.
+
+ # Safety
+
+ `model` must come from `hfmn_model_load`, `data` must point to `len`
+ readable bytes and `out` to writable memory for an `HfmnBuffer`.
+ */
+enum HfmnStatus hfmn_model_encode(const struct HfmnModel *model,
+                                  const uint8_t *data,
+                                  size_t len,
+                                  struct HfmnBuffer *out);
+
+/*
+ Decodes `decoded_len` bytes from bits written by `hfmn_model_encode`.
+
+ # Safety
+
+ `model` must come from `hfmn_model_load`, `bits` must point to `len`
+ readable bytes and `out` to writable memory for an `HfmnBuffer`.
+ */
+enum HfmnStatus hfmn_model_decode(const struct HfmnModel *model,
+                                  const uint8_t *bits,
+                                  size_t len,
+                                  size_t decoded_len,
+                                  struct HfmnBuffer *out);
+
+/*
+ What the last failed call on this thread ran into. Empty before the
+ first failure. The string stays valid until the thread's next failure.
+ */
+const char *hfmn_last_error(void);
+
+/*
+ A fixed description of an `HfmnStatus` value.
+ */
+const char *hfmn_status_string(int status);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* HFMN_H */
@@ -0,0 +1,358 @@
+// C interface to the codec, built as libhfmn. `include/hfmn.h` is generated
+// from this file by running `cbindgen --config cbindgen.toml --output
+// include/hfmn.h` in this directory; the `///` comments end up in the header.
+//
+// Every call returns an `HfmnStatus`. On failure `hfmn_last_error` describes
+// what went wrong on the calling thread. Buffers handed out by the library
+// belong to the caller and go back through `hfmn_buffer_free`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+use huffmancodes::compiled::CompiledModel;
+use huffmancodes::huffman::{HuffmanModel, HuffmanState, Unseen};
+
+/// Result of every call that can fail.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HfmnStatus {
+    Ok = 0,
+    /// A required pointer was null.
+    NullPointer = 1,
+    /// The input is not valid compressed data or a valid model.
+    InvalidData = 2,
+    /// The input holds a byte the model cannot code.
+    InvalidInput = 3,
+    /// Any other failure, including a panic caught at the boundary.
+    Internal = 4,
+}
+
+/// Bytes allocated by the library. Release with `hfmn_buffer_free`.
+#[repr(C)]
+pub struct HfmnBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// A loaded Huffman model. It is immutable, so one model can be used from
+/// several threads at once.
+pub struct HfmnModel {
+    compiled: CompiledModel,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+fn status_of(err: &io::Error) -> HfmnStatus {
+    match err.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => HfmnStatus::InvalidData,
+        io::ErrorKind::InvalidInput => HfmnStatus::InvalidInput,
+        _ => HfmnStatus::Internal,
+    }
+}
+
+// Runs `f`, turning its error or panic into a status and the thread's last
+// error message.
+fn guard(f: impl FnOnce() -> Result<(), (HfmnStatus, String)>) -> HfmnStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => HfmnStatus::Ok,
+        Ok(Err((status, message))) => {
+            set_last_error(&message);
+            status
+        }
+        Err(_) => {
+            set_last_error("internal error");
+            HfmnStatus::Internal
+        }
+    }
+}
+
+fn io_failure(err: io::Error) -> (HfmnStatus, String) {
+    (status_of(&err), err.to_string())
+}
+
+fn null_pointer(name: &str) -> (HfmnStatus, String) {
+    (HfmnStatus::NullPointer, format!("{} is null", name))
+}
+
+// `data` may only be null when `len` is 0
+unsafe fn input<'a>(data: *const u8, len: usize) -> Result<&'a [u8], (HfmnStatus, String)> {
+    if len == 0 {
+        Ok(&[])
+    } else if data.is_null() {
+        Err(null_pointer("data"))
+    } else {
+        Ok(slice::from_raw_parts(data, len))
+    }
+}
+
+unsafe fn output(out: *mut HfmnBuffer, bytes: Vec<u8>) {
+    let len = bytes.len();
+    let data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+    *out = HfmnBuffer { data, len };
+}
+
+/// Compresses `len` bytes at `data` into a self-contained stream, the same
+/// layout `hfmn` writes to files.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes (or may be null when `len` is
+/// 0) and `out` to writable memory for an `HfmnBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn hfmn_compress(data: *const u8, len: usize, out: *mut HfmnBuffer) -> HfmnStatus {
+    guard(|| {
+        if out.is_null() {
+            return Err(null_pointer("out"));
+        }
+        let data = input(data, len)?;
+        let mut bytes = Vec::new();
+        HuffmanState::new(data.to_vec()).save(&mut bytes).map_err(io_failure)?;
+        output(out, bytes);
+        Ok(())
+    })
+}
+
+/// Decompresses a stream from `hfmn_compress`. Fails with
+/// `HFMN_STATUS_INVALID_DATA` rather than produce more than `max_len` bytes;
+/// pass `SIZE_MAX` to trust the input.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes (or may be null when `len` is
+/// 0) and `out` to writable memory for an `HfmnBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn hfmn_decompress(
+    data: *const u8,
+    len: usize,
+    max_len: usize,
+    out: *mut HfmnBuffer,
+) -> HfmnStatus {
+    guard(|| {
+        if out.is_null() {
+            return Err(null_pointer("out"));
+        }
+        let mut data = input(data, len)?;
+        let (state, _) = HuffmanState::load(&mut data, max_len).map_err(io_failure)?;
+        output(out, state.raw_data().to_vec());
+        Ok(())
+    })
+}
+
+/// Frees a buffer returned by the library and resets it to empty. Freeing
+/// an empty buffer does nothing.
+///
+/// # Safety
+///
+/// `buffer` must be null or point to an `HfmnBuffer` filled in by this
+/// library and not freed since.
+#[no_mangle]
+pub unsafe extern "C" fn hfmn_buffer_free(buffer: *mut HfmnBuffer) {
+    if buffer.is_null() || (*buffer).data.is_null() {
+        return;
+    }
+    let HfmnBuffer { data, len } = *buffer;
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    *buffer = HfmnBuffer { data: ptr::null_mut(), len: 0 };
+}
+
+/// Loads a model saved with `HuffmanModel::to_bytes`.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` readable bytes and `out` to writable memory
+/// for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn hfmn_model_load(bytes: *const u8, len: usize, out: *mut *mut HfmnModel) -> HfmnStatus {
+    guard(|| {
+        if out.is_null() {
+            return Err(null_pointer("out"));
+        }
+        let model = HuffmanModel::from_bytes(input(bytes, len)?).map_err(io_failure)?;
+        *out = Box::into_raw(Box::new(HfmnModel { compiled: model.compile() }));
+        Ok(())
+    })
+}
+
+/// Frees a model from `hfmn_model_load`. Null is ignored.
+///
+/// # Safety
+///
+/// `model` must be null or a model from `hfmn_model_load` that has not been
+/// freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn hfmn_model_free(model: *mut HfmnModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/// Huffman codes `len` bytes with `model`, escaping bytes it has no code
+/// for when it can. The output holds only the bits, so the caller keeps
+/// `len` to decode them.
+///
+/// # Safety
+///
+/// `model` must come from `hfmn_model_load`, `data` must point to `len`
+/// readable bytes and `out` to writable memory for an `HfmnBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn hfmn_model_encode(
+    model: *const HfmnModel,
+    data: *const u8,
+    len: usize,
+    out: *mut HfmnBuffer,
+) -> HfmnStatus {
+    guard(|| {
+        let model = model.as_ref().ok_or_else(|| null_pointer("model"))?;
+        if out.is_null() {
+            return Err(null_pointer("out"));
+        }
+        let bits = model.compiled.encode(input(data, len)?, Unseen::Escape).map_err(io_failure)?;
+        output(out, bits);
+        Ok(())
+    })
+}
+
+/// Decodes `decoded_len` bytes from bits written by `hfmn_model_encode`.
+///
+/// # Safety
+///
+/// `model` must come from `hfmn_model_load`, `bits` must point to `len`
+/// readable bytes and `out` to writable memory for an `HfmnBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn hfmn_model_decode(
+    model: *const HfmnModel,
+    bits: *const u8,
+    len: usize,
+    decoded_len: usize,
+    out: *mut HfmnBuffer,
+) -> HfmnStatus {
+    guard(|| {
+        let model = model.as_ref().ok_or_else(|| null_pointer("model"))?;
+        if out.is_null() {
+            return Err(null_pointer("out"));
+        }
+        let bytes = model.compiled.decode(input(bits, len)?, decoded_len).map_err(io_failure)?;
+        output(out, bytes);
+        Ok(())
+    })
+}
+
+/// What the last failed call on this thread ran into. Empty before the
+/// first failure. The string stays valid until the thread's next failure.
+#[no_mangle]
+pub extern "C" fn hfmn_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// A fixed description of an `HfmnStatus` value.
+#[no_mangle]
+pub extern "C" fn hfmn_status_string(status: c_int) -> *const c_char {
+    let text: &'static CStr = match status {
+        0 => c"ok",
+        1 => c"null pointer",
+        2 => c"invalid data",
+        3 => c"byte not in model",
+        4 => c"internal error",
+        _ => c"unknown status",
+    };
+    text.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty() -> HfmnBuffer {
+        HfmnBuffer { data: ptr::null_mut(), len: 0 }
+    }
+
+    unsafe fn take(buffer: &mut HfmnBuffer) -> Vec<u8> {
+        let bytes = slice::from_raw_parts(buffer.data, buffer.len).to_vec();
+        hfmn_buffer_free(buffer);
+        assert!(buffer.data.is_null());
+        bytes
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(hfmn_last_error()) }.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn compress_and_decompress_round_trip() {
+        let data = b"through the C interface and back".repeat(20);
+        unsafe {
+            let mut compressed = empty();
+            assert_eq!(hfmn_compress(data.as_ptr(), data.len(), &mut compressed), HfmnStatus::Ok);
+            let compressed = take(&mut compressed);
+            let mut decompressed = empty();
+            let status = hfmn_decompress(compressed.as_ptr(), compressed.len(), usize::MAX, &mut decompressed);
+            assert_eq!(status, HfmnStatus::Ok);
+            assert_eq!(take(&mut decompressed), data);
+
+            let status = hfmn_decompress(compressed.as_ptr(), compressed.len(), 10, &mut decompressed);
+            assert_eq!(status, HfmnStatus::InvalidData);
+            assert!(decompressed.data.is_null());
+        }
+    }
+
+    #[test]
+    fn empty_input_may_be_null() {
+        unsafe {
+            let mut compressed = empty();
+            assert_eq!(hfmn_compress(ptr::null(), 0, &mut compressed), HfmnStatus::Ok);
+            let compressed = take(&mut compressed);
+            let mut decompressed = empty();
+            let status = hfmn_decompress(compressed.as_ptr(), compressed.len(), 0, &mut decompressed);
+            assert_eq!(status, HfmnStatus::Ok);
+            assert!(take(&mut decompressed).is_empty());
+        }
+    }
+
+    #[test]
+    fn failures_set_the_last_error() {
+        unsafe {
+            let mut out = empty();
+            assert_eq!(hfmn_compress(ptr::null(), 3, &mut out), HfmnStatus::NullPointer);
+            assert_eq!(last_error(), "data is null");
+            assert_eq!(hfmn_compress(b"abc".as_ptr(), 3, ptr::null_mut()), HfmnStatus::NullPointer);
+            assert_eq!(last_error(), "out is null");
+            assert_eq!(hfmn_decompress(b"nope!".as_ptr(), 5, usize::MAX, &mut out), HfmnStatus::InvalidData);
+            assert_eq!(last_error(), "not a huffman file");
+        }
+        let status = unsafe { CStr::from_ptr(hfmn_status_string(HfmnStatus::InvalidData as c_int)) };
+        assert_eq!(status.to_str().unwrap(), "invalid data");
+    }
+
+    #[test]
+    fn models_encode_and_decode() {
+        let bytes = HuffmanModel::from_data(b"model shipped to C").to_bytes();
+        let data = b"C model";
+        unsafe {
+            let mut model = ptr::null_mut();
+            assert_eq!(hfmn_model_load(bytes.as_ptr(), bytes.len(), &mut model), HfmnStatus::Ok);
+            let mut bits = empty();
+            assert_eq!(hfmn_model_encode(model, data.as_ptr(), data.len(), &mut bits), HfmnStatus::Ok);
+            let bits = take(&mut bits);
+            let mut decoded = empty();
+            let status = hfmn_model_decode(model, bits.as_ptr(), bits.len(), data.len(), &mut decoded);
+            assert_eq!(status, HfmnStatus::Ok);
+            assert_eq!(take(&mut decoded), data);
+
+            let mut out = empty();
+            assert_eq!(hfmn_model_encode(model, b"xyz".as_ptr(), 3, &mut out), HfmnStatus::InvalidInput);
+            hfmn_model_free(model);
+
+            assert_eq!(hfmn_model_load(bytes.as_ptr(), 3, &mut model), HfmnStatus::InvalidData);
+        }
+    }
+}
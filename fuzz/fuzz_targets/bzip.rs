@@ -0,0 +1,14 @@
+#![no_main]
+
+use huffmancodes::bzip;
+use libfuzzer_sys::fuzz_target;
+
+// Kept small: the check below sorts every block with the BWT again
+const MAX_LEN: usize = 1 << 16;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(decompressed) = bzip::decompress(data, MAX_LEN) {
+        let compressed = bzip::compress(&decompressed);
+        assert_eq!(bzip::decompress(&compressed, decompressed.len()).unwrap(), decompressed);
+    }
+});
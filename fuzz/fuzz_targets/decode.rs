@@ -0,0 +1,34 @@
+#![no_main]
+
+use huffmancodes::bitio;
+use huffmancodes::frequency::Frequencies;
+use huffmancodes::huffman::{self, HuffmanModel};
+use libfuzzer_sys::fuzz_target;
+
+// The first byte splits the input into a sample the model is trained on and
+// the bits handed to the decoder; the second asks for an escape code and a
+// varint after them is the number of bytes to decode.
+fuzz_target!(|data: &[u8]| {
+    let [split, flags, rest @ ..] = data else {
+        return;
+    };
+    let mut pos = 0;
+    let Some(len) = bitio::read_varint(rest, &mut pos) else {
+        return;
+    };
+    let (sample, bits) = rest[pos..].split_at((*split as usize).min(rest.len() - pos));
+    let freqs = Frequencies::from_data(sample);
+    let model = if flags & 1 != 0 {
+        HuffmanModel::with_escape(&freqs)
+    } else {
+        HuffmanModel::from_frequencies(&freqs)
+    };
+    // Every code is at least a bit, so longer lengths fail anyway
+    let len = len.min(bits.len() as u64 * 8 + 1) as usize;
+    if let Ok(decoded) = huffman::decode(&model, bits, len) {
+        assert_eq!(decoded.len(), len);
+        // Whatever decodes must code back to the same bits
+        let encoded = huffman::encode(&model, &decoded).unwrap();
+        assert_eq!(huffman::decode(&model, &encoded, decoded.len()).unwrap(), decoded);
+    }
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use huffmancodes::dictionary::Dictionary;
+use libfuzzer_sys::fuzz_target;
+
+// A u16 (little endian) length, that many bytes of serialized dictionary,
+// then a message compressed with it
+fuzz_target!(|data: &[u8]| {
+    let [lo, hi, rest @ ..] = data else {
+        return;
+    };
+    let (dictionary, message) = rest.split_at((u16::from_le_bytes([*lo, *hi]) as usize).min(rest.len()));
+    let Ok(dictionary) = Dictionary::from_bytes(dictionary) else {
+        return;
+    };
+    if let Ok(decoded) = dictionary.decompress(message) {
+        let compressed = dictionary.compress(&decoded).unwrap();
+        assert_eq!(dictionary.decompress(&compressed).unwrap(), decoded);
+    }
+});
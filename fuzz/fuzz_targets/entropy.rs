@@ -0,0 +1,16 @@
+#![no_main]
+
+use huffmancodes::entropy::{self, Backend};
+use libfuzzer_sys::fuzz_target;
+
+// A few default sized blocks
+const MAX_LEN: usize = 1 << 18;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(decompressed) = entropy::decompress(data, MAX_LEN) {
+        for backend in [Backend::Huffman, Backend::Range, Backend::Ans] {
+            let compressed = entropy::compress(&decompressed, backend);
+            assert_eq!(entropy::decompress(&compressed, decompressed.len()).unwrap(), decompressed);
+        }
+    }
+});
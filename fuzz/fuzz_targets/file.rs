@@ -0,0 +1,18 @@
+#![no_main]
+
+use huffmancodes::flat::FlatFile;
+use huffmancodes::huffman::HuffmanState;
+use libfuzzer_sys::fuzz_target;
+
+const MAX_LEN: usize = 1 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    let loaded = HuffmanState::load(&mut &data[..], MAX_LEN);
+    // Files without transforms must read the same through the flat decoder
+    if let Ok(file) = FlatFile::parse(data) {
+        if file.len() <= MAX_LEN {
+            let flat = file.decode().ok();
+            assert_eq!(flat.as_deref(), loaded.as_ref().ok().map(|(state, _)| state.raw_data()));
+        }
+    }
+});
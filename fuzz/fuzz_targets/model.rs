@@ -0,0 +1,17 @@
+#![no_main]
+
+use huffmancodes::huffman::{self, HuffmanModel};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(model) = HuffmanModel::from_bytes(data) {
+        // A model that loads must be usable and serialize to something that
+        // loads again
+        let bytes = model.to_bytes();
+        let again = HuffmanModel::from_bytes(&bytes).unwrap();
+        assert_eq!(again.to_bytes(), bytes);
+        if let Ok(bits) = huffman::encode(&model, b"fuzz") {
+            assert_eq!(huffman::decode(&again, &bits, 4).unwrap(), b"fuzz");
+        }
+    }
+});
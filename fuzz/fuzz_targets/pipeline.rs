@@ -0,0 +1,21 @@
+#![no_main]
+
+use huffmancodes::transform::Pipeline;
+use libfuzzer_sys::fuzz_target;
+
+// Kept small: the check below runs the stages forward again, and BWT
+// sorting is the slow part
+const MAX_LEN: usize = 1 << 16;
+
+// A pipeline header followed by data for it to undo
+fuzz_target!(|data: &[u8]| {
+    let mut rest = data;
+    let Ok(pipeline) = Pipeline::read_header(&mut rest) else {
+        return;
+    };
+    if let Ok(decoded) = pipeline.inverse_with_limit(rest, MAX_LEN) {
+        assert!(decoded.len() <= MAX_LEN);
+        let encoded = pipeline.forward(&decoded);
+        assert_eq!(pipeline.inverse(&encoded).unwrap(), decoded);
+    }
+});
@@ -0,0 +1,137 @@
+use std::fmt;
+
+use crate::frequency::Frequencies;
+use crate::huffman::{self, HuffmanModel};
+
+// What Huffman coding would do to a buffer, worked out from its histogram
+// without producing any output. Code lengths are those of the model
+// `HuffmanState::new` builds, so the predicted sizes match what it saves.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Analysis {
+    pub freqs: Frequencies,
+    pub len: usize,
+    // Distinct bytes
+    pub symbols: usize,
+    // Shannon entropy, in bits per byte
+    pub entropy: f64,
+    // Code length averaged over the data, in bits per byte
+    pub mean_code_len: f64,
+    // How far the code is above the entropy, in bits per byte
+    pub redundancy: f64,
+    // Zero when there is no data
+    pub min_code_len: usize,
+    pub max_code_len: usize,
+    // Code length of every byte value, zero for bytes that don't occur
+    pub code_lens: Vec<usize>,
+    // Huffman coded data alone, and as a whole `save_to_file` file, which
+    // stores or run-length codes the data instead where that is smaller
+    pub payload_len: usize,
+    pub file_len: usize,
+}
+
+pub fn analyze(data: &[u8]) -> Analysis {
+    let freqs = Frequencies::from_data(data);
+    let model = HuffmanModel::from_frequencies(&freqs);
+    let compiled = model.compile();
+    let code_lens: Vec<usize> = (0..=255u8).map(|c| compiled.code_len(c).map_or(0, |len| len as usize)).collect();
+
+    let entropy = freqs.entropy();
+    let bits: u64 = freqs.symbols().map(|c| freqs.count(c) * code_lens[c as usize] as u64).sum();
+    let mean_code_len = if data.is_empty() { 0.0 } else { bits as f64 / freqs.total() as f64 };
+    let used = || freqs.symbols().map(|c| code_lens[c as usize]);
+    let payload_len = bits.div_ceil(8) as usize;
+    // The model's bytes are its magic and version followed by the tree
+    let tree_len = model.to_bytes().len() - 5;
+    let (_, body_len) = huffman::file_mode(data, tree_len + payload_len);
+    Analysis {
+        len: data.len(),
+        symbols: freqs.symbols().count(),
+        entropy,
+        mean_code_len,
+        redundancy: mean_code_len - entropy,
+        min_code_len: used().min().unwrap_or(0),
+        max_code_len: used().max().unwrap_or(0),
+        code_lens,
+        payload_len,
+        // Magic and version, the empty pipeline header, the mode and the u64
+        // length
+        file_len: 5 + 1 + 1 + 8 + body_len,
+        freqs,
+    }
+}
+
+impl Analysis {
+    // Number of bytes coded with each length, index 0 unused
+    pub fn length_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.max_code_len + 1];
+        for &len in self.code_lens.iter().filter(|&&len| len > 0) {
+            counts[len] += 1;
+        }
+        counts
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "bytes:            {}", self.len)?;
+        writeln!(f, "symbols:          {}", self.symbols)?;
+        writeln!(f, "entropy:          {:.4} bits/byte", self.entropy)?;
+        writeln!(f, "mean code length: {:.4} bits/byte", self.mean_code_len)?;
+        writeln!(f, "redundancy:       {:.4} bits/byte", self.redundancy)?;
+        writeln!(f, "code lengths:     {}..{}", self.min_code_len, self.max_code_len)?;
+        writeln!(f, "payload:          {} bytes", self.payload_len)?;
+        write!(f, "file:             {} bytes", self.file_len)?;
+        for (len, count) in self.length_counts().into_iter().enumerate().filter(|&(_, count)| count > 0) {
+            write!(f, "\n  {:>3} bits: {} symbols", len, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::huffman::HuffmanState;
+
+    fn saved_len(data: &[u8]) -> usize {
+        let mut bytes = Vec::new();
+        HuffmanState::new(data.to_vec()).save(&mut bytes).unwrap();
+        bytes.len()
+    }
+
+    #[test]
+    fn predicts_what_huffman_state_writes() {
+        for data in [b"".to_vec(), b"aaaa".to_vec(), b"abracadabra".repeat(30), (0..=255).collect()] {
+            let analysis = analyze(&data);
+            assert_eq!(analysis.payload_len, HuffmanState::new(data.clone()).compress().len());
+            assert_eq!(analysis.file_len, saved_len(&data));
+        }
+    }
+
+    #[test]
+    fn measures_entropy_and_redundancy() {
+        // Four equally likely bytes: two bits each, nothing to gain
+        let analysis = analyze(&b"abcd".repeat(16));
+        assert_eq!(analysis.symbols, 4);
+        assert!((analysis.entropy - 2.0).abs() < 1e-9);
+        assert!((analysis.mean_code_len - 2.0).abs() < 1e-9);
+        assert!(analysis.redundancy.abs() < 1e-9);
+        assert_eq!((analysis.min_code_len, analysis.max_code_len), (2, 2));
+        assert_eq!(analysis.length_counts(), vec![0, 0, 4]);
+
+        // A single byte still costs a bit per byte while its entropy is zero
+        let analysis = analyze(b"zzzz");
+        assert_eq!(analysis.entropy, 0.0);
+        assert_eq!(analysis.redundancy, 1.0);
+        assert_eq!(analysis.code_lens[b'z' as usize], 1);
+    }
+
+    #[test]
+    fn empty_input_has_no_codes() {
+        let analysis = analyze(&[]);
+        assert_eq!((analysis.symbols, analysis.min_code_len, analysis.max_code_len), (0, 0, 0));
+        assert_eq!(analysis.entropy, 0.0);
+        assert_eq!(analysis.payload_len, 0);
+    }
+}
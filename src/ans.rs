@@ -0,0 +1,117 @@
+use std::io;
+
+use crate::bitio::{BitReader, BitWriter};
+use crate::frequency::{self, Frequencies};
+
+// Tabled asymmetric numeral systems (tANS), the coder behind zstd's FSE.
+// The normalized counts of the symbols are spread over a table of
+// TABLE_SIZE states; coding a symbol moves between states, emitting the
+// low bits of the state that do not fit in the next one.
+pub const TABLE_LOG: u32 = 11;
+const TABLE_SIZE: u32 = 1 << TABLE_LOG;
+
+#[derive(Clone, Copy, Default)]
+struct DecodeEntry {
+    symbol: u8,
+    bits: u32,
+    base: u32,
+}
+
+pub struct AnsTable {
+    counts: [u32; 256],
+    // First entry of each symbol in `next_state`
+    starts: [u32; 256],
+    next_state: Vec<u32>,
+    decode: Vec<DecodeEntry>,
+}
+
+impl AnsTable {
+    // `counts` must sum to TABLE_SIZE
+    pub fn new(counts: [u32; 256]) -> Self {
+        let mut spread = vec![0u8; TABLE_SIZE as usize];
+        let step = (TABLE_SIZE >> 1) + (TABLE_SIZE >> 3) + 3;
+        let mut pos = 0;
+        for (c, &count) in counts.iter().enumerate() {
+            for _ in 0..count {
+                spread[pos as usize] = c as u8;
+                pos = (pos + step) & (TABLE_SIZE - 1);
+            }
+        }
+
+        let mut starts = [0u32; 256];
+        for c in 1..256 {
+            starts[c] = starts[c - 1] + counts[c - 1];
+        }
+        let mut next = counts;
+        let mut next_state = vec![0u32; TABLE_SIZE as usize];
+        let mut decode = vec![DecodeEntry::default(); TABLE_SIZE as usize];
+        for (state, &c) in spread.iter().enumerate() {
+            let c = c as usize;
+            // The n-th state of a symbol is reached from states
+            // [n << bits, (n + 1) << bits) once the symbol is decoded
+            let n = next[c];
+            next[c] += 1;
+            let bits = TABLE_LOG - n.ilog2();
+            decode[state] = DecodeEntry { symbol: c as u8, bits, base: (n << bits) - TABLE_SIZE };
+            next_state[(starts[c] + n - counts[c]) as usize] = TABLE_SIZE + state as u32;
+        }
+        Self { counts, starts, next_state, decode }
+    }
+
+    pub fn encode(&self, data: &[u8], writer: &mut BitWriter) {
+        // Symbols are coded last to first so they decode first to last;
+        // the bits come out in the same reversed order
+        let mut state = TABLE_SIZE;
+        let mut chunks = Vec::with_capacity(data.len());
+        for &c in data.iter().rev() {
+            let count = self.counts[c as usize];
+            let mut bits = 0;
+            while (state >> bits) >= 2 * count {
+                bits += 1;
+            }
+            chunks.push((state & ((1 << bits) - 1), bits));
+            state = self.next_state[(self.starts[c as usize] + (state >> bits) - count) as usize];
+        }
+        writer.push_bits((state - TABLE_SIZE) as u64, TABLE_LOG);
+        for &(value, bits) in chunks.iter().rev() {
+            writer.push_bits(value as u64, bits);
+        }
+    }
+
+    pub fn decode(&self, reader: &mut BitReader, len: usize) -> io::Result<Vec<u8>> {
+        let truncated = || invalid("truncated ans stream");
+        let mut state = reader.read_bits(TABLE_LOG).ok_or_else(truncated)? as u32;
+        let mut out = Vec::with_capacity(len);
+        for _ in 0..len {
+            let entry = self.decode[state as usize];
+            out.push(entry.symbol);
+            state = entry.base + reader.read_bits(entry.bits).ok_or_else(truncated)? as u32;
+        }
+        Ok(out)
+    }
+}
+
+// Payload layout: the header written by `frequency::write_header`, then
+// the coded bits.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let counts = Frequencies::from_data(data).normalized(TABLE_SIZE);
+    let mut out = frequency::write_header(data.len(), &counts);
+    if !data.is_empty() {
+        let mut writer = BitWriter::new();
+        AnsTable::new(counts).encode(data, &mut writer);
+        out.extend(writer.finish());
+    }
+    out
+}
+
+pub fn decode(payload: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let Some((len, counts, pos)) = frequency::read_header(payload, TABLE_SIZE, max_len)? else {
+        return Ok(Vec::new());
+    };
+    let mut reader = BitReader::new(&payload[pos..]);
+    AnsTable::new(counts).decode(&mut reader, len)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::entropy::Backend;
+use crate::frequency::Frequencies;
+use crate::huffman::HuffmanState;
+
+// Throughput and ratio measurements of `HuffmanState::compress` and
+// `decompress` and of the range and ANS coders, shared by `cargo bench` and
+// `hfmn bench`.
+pub const CORPUS_SIZE: usize = 1 << 20;
+
+pub struct Report {
+    pub name: String,
+    pub coder: &'static str,
+    pub len: usize,
+    // Coded data alone, without the table
+    pub compressed_len: usize,
+    // Serialized tree or frequency table, the price of shipping the code
+    // with the data
+    pub table_len: usize,
+    pub compress_time: Duration,
+    pub decompress_time: Duration,
+}
+
+impl Report {
+    pub fn compress_mb_per_sec(&self) -> f64 {
+        mb_per_sec(self.len, self.compress_time)
+    }
+    pub fn decompress_mb_per_sec(&self) -> f64 {
+        mb_per_sec(self.len, self.decompress_time)
+    }
+    pub fn bits_per_symbol(&self) -> f64 {
+        if self.len == 0 {
+            return 0.0;
+        }
+        (self.compressed_len * 8) as f64 / self.len as f64
+    }
+    pub fn header() -> String {
+        format!(
+            "{:<12} {:<7} {:>9} {:>10} {:>12} {:>9} {:>12}",
+            "name", "coder", "bytes", "comp MB/s", "decomp MB/s", "bits/sym", "table bytes"
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<12} {:<7} {:>9} {:>10.1} {:>12.1} {:>9.3} {:>12}",
+            self.name,
+            self.coder,
+            self.len,
+            self.compress_mb_per_sec(),
+            self.decompress_mb_per_sec(),
+            self.bits_per_symbol(),
+            self.table_len
+        )
+    }
+}
+
+fn mb_per_sec(len: usize, time: Duration) -> f64 {
+    len as f64 / (1 << 20) as f64 / time.as_secs_f64().max(1e-9)
+}
+
+// Compresses and decompresses `data` `iterations` times, keeping the fastest
+// run of each so a busy machine skews the numbers less.
+pub fn run(name: &str, data: &[u8], iterations: usize) -> Report {
+    assert!(iterations > 0);
+    let hfmn = HuffmanState::new(data.to_vec());
+    let mut compressed = Vec::new();
+    let mut compress_time = Duration::MAX;
+    let mut decompress_time = Duration::MAX;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        compressed = hfmn.compress();
+        compress_time = compress_time.min(start.elapsed());
+
+        let start = Instant::now();
+        let decompressed = hfmn.decompress(compressed.clone()).expect("round trip");
+        decompress_time = decompress_time.min(start.elapsed());
+        assert!(decompressed == data, "{} did not round trip", name);
+    }
+    Report {
+        name: name.to_string(),
+        coder: "huffman",
+        len: data.len(),
+        compressed_len: compressed.len(),
+        table_len: hfmn.model().to_bytes().len(),
+        compress_time,
+        decompress_time,
+    }
+}
+
+// Same measurement of the range or ANS coder over the whole of `data`
+pub fn run_backend(name: &str, data: &[u8], backend: Backend, iterations: usize) -> Report {
+    assert!(iterations > 0);
+    let coder = match backend {
+        Backend::Range => "range",
+        Backend::Ans => "ans",
+        _ => panic!("{:?} is not benchmarked on its own", backend),
+    };
+    let mut payload = Vec::new();
+    let mut compress_time = Duration::MAX;
+    let mut decompress_time = Duration::MAX;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        payload = backend.encode(data);
+        compress_time = compress_time.min(start.elapsed());
+
+        let start = Instant::now();
+        let decompressed = backend.decode(&payload, data.len()).expect("round trip");
+        decompress_time = decompress_time.min(start.elapsed());
+        assert!(decompressed == data, "{} did not round trip through {}", name, coder);
+    }
+    // The payload opens with the length (u64) and the frequency table: a
+    // presence bitmap and a u16 per byte present
+    let table_len = 32 + 2 * Frequencies::from_data(data).symbols().count();
+    Report {
+        name: name.to_string(),
+        coder,
+        len: data.len(),
+        compressed_len: payload.len() - 8 - table_len,
+        table_len,
+        compress_time,
+        decompress_time,
+    }
+}
+
+// A row for Huffman, range and ANS coding of `data`
+pub fn run_all(name: &str, data: &[u8], iterations: usize) -> Vec<Report> {
+    let mut reports = vec![run(name, data, iterations)];
+    for backend in [Backend::Range, Backend::Ans] {
+        reports.push(run_backend(name, data, backend, iterations));
+    }
+    reports
+}
+
+// The built-in corpus. Every sample comes from a fixed seed, so numbers from
+// different runs and machines measure the same bytes.
+pub fn corpus() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("text", text(CORPUS_SIZE)),
+        ("random", random(CORPUS_SIZE)),
+        ("skewed", skewed(CORPUS_SIZE)),
+        ("binary", binary(CORPUS_SIZE)),
+    ]
+}
+
+// xorshift64, enough to make reproducible noise
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// Words drawn with a bias towards the front of the list, separated by
+// spaces and the odd line break
+fn text(len: usize) -> Vec<u8> {
+    const WORDS: &[&str] = &[
+        "the", "of", "and", "to", "a", "in", "is", "that", "for", "it", "as", "was", "with", "be", "by", "on",
+        "not", "he", "this", "are", "or", "his", "from", "at", "which", "but", "have", "an", "had", "they",
+        "huffman", "tree", "code", "symbol", "frequency", "encoding", "decoder", "stream", "block", "table",
+    ];
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut out = Vec::with_capacity(len + 16);
+    while out.len() < len {
+        let pick = (rng.next() % WORDS.len() as u64) as usize;
+        let word = WORDS[pick.min((rng.next() % WORDS.len() as u64) as usize)];
+        out.extend_from_slice(word.as_bytes());
+        out.push(if rng.next().is_multiple_of(12) { b'\n' } else { b' ' });
+    }
+    out.truncate(len);
+    out
+}
+
+fn random(len: usize) -> Vec<u8> {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    (0..len).map(|_| rng.next() as u8).collect()
+}
+
+// Geometric distribution: each byte value is half as likely as the last
+fn skewed(len: usize) -> Vec<u8> {
+    let mut rng = Rng(0xdead_beef_cafe_f00d);
+    (0..len).map(|_| rng.next().trailing_zeros().min(255) as u8).collect()
+}
+
+// Little endian u16 samples of a noisy slow wave, like sensor or audio data
+fn binary(len: usize) -> Vec<u8> {
+    let mut rng = Rng(0x0123_4567_89ab_cdef);
+    let mut out = Vec::with_capacity(len + 1);
+    let mut i = 0u32;
+    while out.len() < len {
+        let wave = ((i as f64 / 64.0).sin() * 8000.0) as i32 + 16000;
+        let sample = (wave + (rng.next() % 64) as i32) as u16;
+        out.extend_from_slice(&sample.to_le_bytes());
+        i += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corpus_is_reproducible() {
+        let (a, b) = (corpus(), corpus());
+        for ((name, data), (_, again)) in a.iter().zip(&b) {
+            assert_eq!(data.len(), CORPUS_SIZE, "{}", name);
+            assert!(data == again, "{} changed between runs", name);
+        }
+    }
+
+    #[test]
+    fn report_counts_bits_per_symbol() {
+        let report = run("single", &[b'x'; 800], 1);
+        assert_eq!(report.compressed_len, 100);
+        assert_eq!(report.bits_per_symbol(), 1.0);
+    }
+
+    #[test]
+    fn every_coder_gets_a_row() {
+        let data = text(20_000);
+        let reports = run_all("text", &data, 1);
+        let coders: Vec<_> = reports.iter().map(|r| r.coder).collect();
+        assert_eq!(coders, ["huffman", "range", "ans"]);
+        for report in &reports {
+            assert!(report.bits_per_symbol() > 3.0 && report.bits_per_symbol() < 6.0, "{}", report);
+        }
+        let range = &reports[1];
+        assert_eq!(8 + range.table_len + range.compressed_len, Backend::Range.encode(&data).len());
+    }
+}
@@ -1,21 +1,24 @@
 use core::fmt;
-use std::{rc::*, cell::RefCell};
+use std::{rc::*, cell::RefCell, collections::{HashMap, VecDeque}, hash::Hash, io, ops::{Bound, RangeBounds}};
 
 
 pub struct BinTree<T>{
     pub val : T,
-    pub parent : Option<Rc<RefCell<BinTree<T>>>>,
+    // Weak so that a tree is freed once the last handle to its root goes
+    pub parent : Option<Weak<RefCell<BinTree<T>>>>,
     pub right : Option<Rc<RefCell<BinTree<T>>>>,
     pub left : Option<Rc<RefCell<BinTree<T>>>>,
 }
 impl <T: Ord> PartialOrd for BinTree<T>{
     fn partial_cmp(&self, rhs: &Self) -> Option<std::cmp::Ordering> { 
-        self.val.partial_cmp(&rhs.val)
+        Some(self.cmp(rhs))
     }
 }
+// Comparisons look at the node's own value only, like `Ord`; `deep_eq`
+// compares whole trees.
 impl <T: Ord> PartialEq for BinTree<T>{
     fn eq(&self, self2: &Self) -> bool {
-        self.val.eq(&self2.val) && self.right.eq(&self2.right) && self.left.eq(&self2.left)
+        self.val.eq(&self2.val)
     }
 }
 impl <T: Ord> Eq for BinTree<T>{}
@@ -25,6 +28,10 @@ impl <T: Ord> Ord for BinTree<T>{
         self.val.cmp(&self2.val)
     }
 }
+// Shared handle to a node, the form trees are built and walked in
+pub type TreeRef<T> = Rc<RefCell<BinTree<T>>>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Side{
     Left,
     Right
@@ -45,7 +52,161 @@ impl<T> BinTree<T> {
             right: None, 
             left: None }))
     }
+    // The parent, unless this is a root or the parent has been dropped
+    pub fn parent(&self) -> Option<TreeRef<T>> {
+        self.parent.as_ref().and_then(Weak::upgrade)
+    }
+}
+
+// Children are freed from a stack instead of recursively, so dropping a
+// very deep tree cannot overflow ours.
+impl<T> Drop for BinTree<T> {
+    fn drop(&mut self) {
+        let mut stack: Vec<TreeRef<T>> = self.left.take().into_iter().chain(self.right.take()).collect();
+        while let Some(node) = stack.pop() {
+            // Subtrees still referenced elsewhere stay alive
+            if let Ok(node) = Rc::try_unwrap(node) {
+                let mut node = node.into_inner();
+                stack.extend(node.left.take());
+                stack.extend(node.right.take());
+            }
+        }
+    }
+}
+impl<T> BinTree<T> {
+    // Copy of the tree with every value passed through `f`, in preorder.
+    pub fn map<U>(&self, mut f: impl FnMut(&T) -> U) -> TreeRef<U> {
+        let root = BinTree::as_ref(f(&self.val));
+        let mut stack = Vec::new();
+        push_children(&mut stack, self, &root);
+        while let Some((src, parent, side)) = stack.pop() {
+            let src = src.borrow();
+            let node = BinTree::as_ref(f(&src.val));
+            add_tree(parent, node.clone(), side);
+            push_children(&mut stack, &src, &node);
+        }
+        root
+    }
+
+    // Combines the values bottom up: `f` gets a node's value and the results
+    // for its left and right subtrees, `empty` standing in for a missing one.
+    pub fn fold<A: Clone>(&self, empty: A, mut f: impl FnMut(&T, A, A) -> A) -> A {
+        let mut fold_child = |child: &Option<TreeRef<T>>| match child {
+            None => empty.clone(),
+            Some(child) => {
+                // Postorder finishes both subtrees right before their
+                // parent, so their results are on top of the stack
+                let mut results = Vec::new();
+                for (node, _) in postorder(child.clone()) {
+                    let n = node.borrow();
+                    let right = if n.right.is_some() { results.pop().unwrap() } else { empty.clone() };
+                    let left = if n.left.is_some() { results.pop().unwrap() } else { empty.clone() };
+                    results.push(f(&n.val, left, right));
+                }
+                results.pop().unwrap()
+            }
+        };
+        let left = fold_child(&self.left);
+        let right = fold_child(&self.right);
+        f(&self.val, left, right)
+    }
+
+    // Whether both trees have children in exactly the same places.
+    pub fn same_shape<U>(&self, other: &BinTree<U>) -> bool {
+        walk_pairs(self, other, |_, _| true)
+    }
+
+    // Pairs up the values of two trees of the same shape; None if the
+    // shapes differ.
+    pub fn zip_with<U, V>(&self, other: &BinTree<U>, mut f: impl FnMut(&T, &U) -> V) -> Option<TreeRef<V>> {
+        if !self.same_shape(other) {
+            return None;
+        }
+        let root = BinTree::as_ref(f(&self.val, &other.val));
+        let mut stack = Vec::new();
+        for (a, b, side) in [(&self.left, &other.left, Side::Left), (&self.right, &other.right, Side::Right)] {
+            if let (Some(a), Some(b)) = (a, b) {
+                stack.push((a.clone(), b.clone(), root.clone(), side));
+            }
+        }
+        while let Some((a, b, parent, side)) = stack.pop() {
+            let (a, b) = (a.borrow(), b.borrow());
+            let node = BinTree::as_ref(f(&a.val, &b.val));
+            add_tree(parent, node.clone(), side);
+            if let (Some(a), Some(b)) = (&a.right, &b.right) {
+                stack.push((a.clone(), b.clone(), node.clone(), Side::Right));
+            }
+            if let (Some(a), Some(b)) = (&a.left, &b.left) {
+                stack.push((a.clone(), b.clone(), node, Side::Left));
+            }
+        }
+        Some(root)
+    }
+}
+
+impl<T: PartialEq> BinTree<T> {
+    // Same shape and equal values everywhere. Unlike `==`, which only
+    // compares the values at the two roots.
+    pub fn deep_eq(&self, other: &Self) -> bool {
+        walk_pairs(self, other, |a, b| a == b)
+    }
+}
+
+// A copy of the whole subtree. Its root is not behind an `Rc` yet, so the
+// children of the copy have no parent; `deep_clone` returns a fully linked
+// copy.
+impl<T: Clone> Clone for BinTree<T> {
+    fn clone(&self) -> Self {
+        let copy = self.map(T::clone);
+        let mut copy = copy.borrow_mut();
+        for child in [&copy.left, &copy.right].into_iter().flatten() {
+            child.borrow_mut().parent = None;
+        }
+        Self { val: self.val.clone(), parent: None, left: copy.left.take(), right: copy.right.take() }
+    }
+}
+
+pub fn deep_clone<T: Clone>(tree: &TreeRef<T>) -> TreeRef<T> {
+    tree.borrow().map(T::clone)
+}
+
+type MapWork<T, U> = (TreeRef<T>, TreeRef<U>, Side);
+
+fn push_children<T, U>(stack: &mut Vec<MapWork<T, U>>, src: &BinTree<T>, dst: &TreeRef<U>) {
+    if let Some(right) = &src.right {
+        stack.push((right.clone(), dst.clone(), Side::Right));
+    }
+    if let Some(left) = &src.left {
+        stack.push((left.clone(), dst.clone(), Side::Left));
+    }
+}
+
+// Walks two trees side by side, failing as soon as their shapes differ or
+// `visit` rejects a pair of values.
+fn walk_pairs<T, U>(a: &BinTree<T>, b: &BinTree<U>, mut visit: impl FnMut(&T, &U) -> bool) -> bool {
+    fn children<T, U>(a: &BinTree<T>, b: &BinTree<U>, stack: &mut Vec<(TreeRef<T>, TreeRef<U>)>) -> bool {
+        for pair in [(&a.left, &b.left), (&a.right, &b.right)] {
+            match pair {
+                (Some(a), Some(b)) => stack.push((a.clone(), b.clone())),
+                (None, None) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+    let mut stack = Vec::new();
+    if !visit(&a.val, &b.val) || !children(a, b, &mut stack) {
+        return false;
+    }
+    while let Some((a, b)) = stack.pop() {
+        let (a, b) = (a.borrow(), b.borrow());
+        if !visit(&a.val, &b.val) || !children(&a, &b, &mut stack) {
+            return false;
+        }
+    }
+    true
 }
+
 impl<T: fmt::Display> BinTree<T> {
     pub fn print_tree(&self){
         self.print_tree_depth(0)
@@ -55,19 +216,19 @@ impl<T: fmt::Display> BinTree<T> {
             print!("-")
         }
         println!("{}", self.val);
-        if self.left.is_some() {
+        if let Some(left) = &self.left {
             print!("L");
-            self.left.as_ref().unwrap().borrow().print_tree_depth(depth + 1)
+            left.borrow().print_tree_depth(depth + 1)
         }
-        if self.right.is_some() {
+        if let Some(right) = &self.right {
             print!("R");
-            self.right.as_ref().unwrap().borrow().print_tree_depth(depth + 1)
+            right.borrow().print_tree_depth(depth + 1)
         }
     }
 }
 
 pub fn add_tree<T>(tree: Rc<RefCell<BinTree<T>>>, child: Rc<RefCell<BinTree<T>>>, side: Side){
-    child.borrow_mut().parent = Option::Some(tree.clone());
+    child.borrow_mut().parent = Option::Some(Rc::downgrade(&tree));
     match side {
         Side::Left => {tree.borrow_mut().left = Option::Some(child.clone());}
         Side::Right => {tree.borrow_mut().right = Option::Some(child.clone());}
@@ -80,60 +241,1230 @@ pub fn add_element<T>(tree: Rc<RefCell<BinTree<T>>>, val: T, side: Side) {
 pub fn is_next_in_order<T>(tree: Rc<RefCell<BinTree<T>>>, is_stack_empty: bool) -> bool {
     let exists_right = tree.borrow_mut().right.is_some();
 
-    return exists_right || !is_stack_empty;
+    exists_right || !is_stack_empty
 }
 pub fn has_parent<T>(tree: Rc<RefCell<BinTree<T>>>) -> bool {
-    tree.borrow_mut().parent.is_some()
+    tree.borrow().parent().is_some()
+}
+
+// The node's parent, grandparent and so on up to the root.
+pub fn ancestors<T>(tree: TreeRef<T>) -> Ancestors<T> {
+    Ancestors { next: tree.borrow().parent() }
+}
+
+pub struct Ancestors<T> {
+    next: Option<TreeRef<T>>,
+}
+
+impl<T> Iterator for Ancestors<T> {
+    type Item = TreeRef<T>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.next.take()?;
+        self.next = node.borrow().parent();
+        Some(node)
+    }
+}
+
+// The sides taken on the way from the root down to the node, found by
+// climbing the parent links. For a Huffman leaf this is its code.
+pub fn path_to_root<T>(tree: TreeRef<T>) -> Vec<Side> {
+    let mut path = Vec::new();
+    let mut child = tree;
+    loop {
+        let parent = child.borrow().parent();
+        let Some(parent) = parent else {
+            break;
+        };
+        let is_left = parent.borrow().left.as_ref().is_some_and(|left| Rc::ptr_eq(left, &child));
+        path.push(if is_left { Side::Left } else { Side::Right });
+        child = parent;
+    }
+    path.reverse();
+    path
 }
 
 pub fn get_size<T>(tree: Rc<RefCell<BinTree<T>>>) -> u64{
-    let left = if tree.borrow_mut().left.is_some(){
-        get_size(tree.borrow_mut().left.as_ref().unwrap().clone())
-    } else {0};
-    let right = if tree.borrow_mut().right.is_some(){
-        get_size(tree.borrow_mut().right.as_ref().unwrap().clone())
-    } else {0};
-    return left + right + 1;
-}
-
-pub fn create_from_orders<T: Eq + Clone + Copy + fmt::Display>(inorder: Vec<T>, mut preorder: Vec<T>) -> Rc<RefCell<BinTree<T>>> {
-    assert!(preorder.len() >= 1);
-    let t = preorder.remove(0);
-    let curr_node: Rc<RefCell<BinTree<T>>> = BinTree::as_ref(t); 
-    if inorder.len() <= 1 {
-        return curr_node;
-    } 
-    let mut split_option: Option<usize> = None;
-    for i in 0..inorder.len() {
-        let t2 = inorder[i].clone();
-        // println!("{} == {}", t, t2);
-        if t == t2 {
-            split_option = Some(i);
+    preorder(tree).count() as u64
+}
+
+// Traversals yield every node together with its depth, the root being at
+// depth 0. They keep their own stack, so deep trees cannot overflow ours.
+pub fn preorder<T>(tree: TreeRef<T>) -> Preorder<T> {
+    Preorder { stack: vec![(tree, 0)] }
+}
+pub fn inorder<T>(tree: TreeRef<T>) -> Inorder<T> {
+    Inorder { stack: Vec::new(), current: Some((tree, 0)) }
+}
+pub fn postorder<T>(tree: TreeRef<T>) -> Postorder<T> {
+    Postorder { stack: vec![(tree, 0, false)] }
+}
+pub fn level_order<T>(tree: TreeRef<T>) -> LevelOrder<T> {
+    LevelOrder { queue: VecDeque::from([(tree, 0)]) }
+}
+
+pub struct Preorder<T> {
+    stack: Vec<(TreeRef<T>, usize)>,
+}
+
+impl<T> Iterator for Preorder<T> {
+    type Item = (TreeRef<T>, usize);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, depth) = self.stack.pop()?;
+        {
+            let curr = node.borrow();
+            if let Some(right) = &curr.right {
+                self.stack.push((right.clone(), depth + 1));
+            }
+            if let Some(left) = &curr.left {
+                self.stack.push((left.clone(), depth + 1));
+            }
+        }
+        Some((node, depth))
+    }
+}
+
+pub struct Inorder<T> {
+    stack: Vec<(TreeRef<T>, usize)>,
+    // Next subtree whose left spine still has to be pushed
+    current: Option<(TreeRef<T>, usize)>,
+}
+
+impl<T> Iterator for Inorder<T> {
+    type Item = (TreeRef<T>, usize);
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, depth)) = self.current.take() {
+            self.current = node.borrow().left.clone().map(|left| (left, depth + 1));
+            self.stack.push((node, depth));
+        }
+        let (node, depth) = self.stack.pop()?;
+        self.current = node.borrow().right.clone().map(|right| (right, depth + 1));
+        Some((node, depth))
+    }
+}
+
+pub struct Postorder<T> {
+    // The flag marks nodes whose children are already on the stack
+    stack: Vec<(TreeRef<T>, usize, bool)>,
+}
+
+impl<T> Iterator for Postorder<T> {
+    type Item = (TreeRef<T>, usize);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, depth, expanded) = self.stack.pop()?;
+            if expanded {
+                return Some((node, depth));
+            }
+            let (left, right) = {
+                let curr = node.borrow();
+                (curr.left.clone(), curr.right.clone())
+            };
+            self.stack.push((node, depth, true));
+            if let Some(right) = right {
+                self.stack.push((right, depth + 1, false));
+            }
+            if let Some(left) = left {
+                self.stack.push((left, depth + 1, false));
+            }
+        }
+    }
+}
+
+pub struct LevelOrder<T> {
+    queue: VecDeque<(TreeRef<T>, usize)>,
+}
+
+impl<T> Iterator for LevelOrder<T> {
+    type Item = (TreeRef<T>, usize);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, depth) = self.queue.pop_front()?;
+        {
+            let curr = node.borrow();
+            if let Some(left) = &curr.left {
+                self.queue.push_back((left.clone(), depth + 1));
+            }
+            if let Some(right) = &curr.right {
+                self.queue.push_back((right.clone(), depth + 1));
+            }
+        }
+        Some((node, depth))
+    }
+}
+
+// Rebuilds a tree from its inorder and preorder traversals. Fails when the
+// two traversals do not describe the same tree. Values must be unique; use
+// `serialize` and `deserialize` for trees that repeat them.
+pub fn create_from_orders<T: Eq + Hash + Copy>(inorder: Vec<T>, preorder: Vec<T>) -> io::Result<Rc<RefCell<BinTree<T>>>> {
+    rebuild_from_runs(&inorder, &preorder, true)
+}
+
+// Same as `create_from_orders`, from the inorder and postorder traversals.
+pub fn create_from_postorder<T: Eq + Hash + Copy>(inorder: Vec<T>, postorder: Vec<T>) -> io::Result<TreeRef<T>> {
+    rebuild_from_runs(&inorder, &postorder, false)
+}
+
+// Same as `create_from_orders`, from the inorder and level-order traversals.
+pub fn create_from_level_order<T: Eq + Hash + Copy>(inorder: Vec<T>, levels: Vec<T>) -> io::Result<TreeRef<T>> {
+    check_lengths(&inorder, &levels)?;
+    let positions = inorder_positions(&inorder)?;
+    // A subtree's root is the first of its values in level order, so the
+    // tree is the Cartesian tree of each inorder value's level-order rank
+    let mut rank = vec![usize::MAX; inorder.len()];
+    for (i, val) in levels.iter().enumerate() {
+        let pos = find_root(&positions, val, 0, inorder.len())?;
+        if rank[pos] != usize::MAX {
+            return Err(mismatch());
+        }
+        rank[pos] = i;
+    }
+    let mut left = vec![None; inorder.len()];
+    let mut right = vec![None; inorder.len()];
+    // Right spine of the tree built so far, from the root down
+    let mut spine: Vec<usize> = Vec::new();
+    for pos in 0..inorder.len() {
+        let mut last = None;
+        while spine.last().is_some_and(|&top| rank[top] > rank[pos]) {
+            last = spine.pop();
+        }
+        left[pos] = last;
+        if let Some(&top) = spine.last() {
+            right[top] = Some(pos);
+        }
+        spine.push(pos);
+    }
+    let nodes: Vec<TreeRef<T>> = inorder.iter().map(|&val| BinTree::as_ref(val)).collect();
+    for (pos, node) in nodes.iter().enumerate() {
+        if let Some(child) = left[pos] {
+            add_tree(node.clone(), nodes[child].clone(), Side::Left);
+        }
+        if let Some(child) = right[pos] {
+            add_tree(node.clone(), nodes[child].clone(), Side::Right);
+        }
+    }
+    let root = nodes[spine[0]].clone();
+    // The ranks only place each subtree's root; the levels themselves may
+    // still be out of order
+    if !level_order(root.clone()).map(|(node, _)| node.borrow().val).eq(levels.iter().copied()) {
+        return Err(mismatch());
+    }
+    Ok(root)
+}
+
+// Rebuilds a tree from its inorder traversal and one that lists every
+// subtree as a contiguous run, root first (preorder) or last (postorder),
+// left subtree before right. Pending subtrees wait on a heap stack as
+// inorder ranges, so a long chain costs neither recursion nor copies.
+fn rebuild_from_runs<T: Eq + Hash + Copy>(inorder: &[T], other: &[T], root_first: bool) -> io::Result<TreeRef<T>> {
+    check_lengths(inorder, other)?;
+    let positions = inorder_positions(inorder)?;
+    let mut root = None;
+    // Where each pending subtree hangs, its inorder range and the start of
+    // its run in `other`
+    let mut stack = vec![(None, 0, inorder.len(), 0)];
+    while let Some((parent, start, end, run)) = stack.pop() {
+        let (val, children) = if root_first { (other[run], run + 1) } else { (other[run + end - start - 1], run) };
+        let split = find_root(&positions, &val, start, end)?;
+        let node = BinTree::as_ref(val);
+        match parent {
+            Some((parent, side)) => add_tree(parent, node.clone(), side),
+            None => root = Some(node.clone()),
+        }
+        // Either side may be missing
+        if split > start {
+            stack.push((Some((node.clone(), Side::Left)), start, split, children));
+        }
+        if split + 1 < end {
+            stack.push((Some((node, Side::Right)), split + 1, end, children + split - start));
+        }
+    }
+    Ok(root.expect("traversals are not empty"))
+}
+
+fn check_lengths<T>(inorder: &[T], other: &[T]) -> io::Result<()> {
+    if inorder.is_empty() || inorder.len() != other.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "traversals differ in length"));
+    }
+    Ok(())
+}
+
+// Position of every value in the inorder traversal
+fn inorder_positions<T: Eq + Hash + Copy>(inorder: &[T]) -> io::Result<HashMap<T, usize>> {
+    let mut positions = HashMap::with_capacity(inorder.len());
+    for (pos, &val) in inorder.iter().enumerate() {
+        if positions.insert(val, pos).is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "traversals repeat a value"));
+        }
+    }
+    Ok(positions)
+}
+
+// Inorder position of `root`, which must lie in `start..end`
+fn find_root<T: Eq + Hash>(positions: &HashMap<T, usize>, root: &T, start: usize, end: usize) -> io::Result<usize> {
+    positions.get(root).copied().filter(|pos| (start..end).contains(pos)).ok_or_else(mismatch)
+}
+
+fn mismatch() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "traversals do not describe the same tree")
+}
+
+// Preorder listing of the tree with `None` wherever a child is missing.
+// Unlike a pair of traversals it pins down the shape on its own, so values
+// may repeat.
+pub fn serialize<T: Clone>(tree: TreeRef<T>) -> Vec<Option<T>> {
+    let mut out = Vec::new();
+    let mut stack = vec![Some(tree)];
+    while let Some(node) = stack.pop() {
+        match node {
+            None => out.push(None),
+            Some(node) => {
+                let node = node.borrow();
+                out.push(Some(node.val.clone()));
+                stack.push(node.right.clone());
+                stack.push(node.left.clone());
+            }
+        }
+    }
+    out
+}
+
+// Inverse of `serialize`. Fails unless `items` describes exactly one tree.
+pub fn deserialize<T: Clone>(items: &[Option<T>]) -> io::Result<TreeRef<T>> {
+    let malformed = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let root = match items.first() {
+        Some(Some(val)) => BinTree::as_ref(val.clone()),
+        _ => return Err(malformed("serialized tree has no root")),
+    };
+    // Nodes whose children are still being read, and whether the left one
+    // has been
+    let mut pending = vec![(root.clone(), false)];
+    for item in &items[1..] {
+        let (parent, left_done) = pending.pop().ok_or_else(|| malformed("serialized tree has extra items"))?;
+        let side = if left_done { Side::Right } else { Side::Left };
+        if !left_done {
+            pending.push((parent.clone(), true));
+        }
+        if let Some(val) = item {
+            let child = BinTree::as_ref(val.clone());
+            add_tree(parent, child.clone(), side);
+            pending.push((child, false));
+        }
+    }
+    if !pending.is_empty() {
+        return Err(malformed("serialized tree is truncated"));
+    }
+    Ok(root)
+}
+
+// With the `serde` feature a tree is stored the way `serialize` lays it out,
+// a flat preorder list with null markers, so deep trees stay clear of the
+// nesting limits of formats like JSON.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for BinTree<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(None)?;
+        seq.serialize_element(&Some(&self.val))?;
+        let mut stack = vec![self.right.clone(), self.left.clone()];
+        while let Some(node) = stack.pop() {
+            match node {
+                None => seq.serialize_element(&None::<&T>)?,
+                Some(node) => {
+                    let node = node.borrow();
+                    seq.serialize_element(&Some(&node.val))?;
+                    stack.push(node.right.clone());
+                    stack.push(node.left.clone());
+                }
+            }
+        }
+        seq.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de> + Clone> serde::Deserialize<'de> for BinTree<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let items = Vec::<Option<T>>::deserialize(deserializer)?;
+        let root = deserialize(&items).map_err(serde::de::Error::custom)?;
+        let root = match Rc::try_unwrap(root) {
+            Ok(root) => root.into_inner(),
+            Err(_) => unreachable!("nothing else holds the new root"),
+        };
+        // Like `clone`, the children of a tree owned by value have no parent
+        for child in [&root.left, &root.right].into_iter().flatten() {
+            child.borrow_mut().parent = None;
+        }
+        Ok(root)
+    }
+}
+
+// Binary search tree over `BinTree` nodes, holding each value at most once.
+// Unlike a bare `BinTree` it can be empty.
+pub struct SearchTree<T> {
+    root: Option<TreeRef<T>>,
+    len: usize,
+}
+
+impl<T> Default for SearchTree<T> {
+    fn default() -> Self {
+        Self { root: None, len: 0 }
+    }
+}
+
+impl<T: Ord + Clone> SearchTree<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    // For walking the tree with the traversal iterators
+    pub fn root(&self) -> Option<TreeRef<T>> {
+        self.root.clone()
+    }
+
+    // Returns false, leaving the tree alone, if `val` is already present.
+    pub fn insert(&mut self, val: T) -> bool {
+        let inserted = insert_by(&mut self.root, val, |val| val, |val| val).is_some();
+        self.len += usize::from(inserted);
+        inserted
+    }
+    pub fn contains(&self, val: &T) -> bool {
+        find_by(&self.root, val, |val| val).is_some()
+    }
+    // Returns whether `val` was present.
+    pub fn remove(&mut self, val: &T) -> bool {
+        let removed = remove_by(&mut self.root, val, |val| val, std::mem::swap).is_some();
+        self.len -= usize::from(removed);
+        removed
+    }
+
+    pub fn min(&self) -> Option<T> {
+        self.root.clone().map(|root| leftmost(root).borrow().val.clone())
+    }
+    pub fn max(&self) -> Option<T> {
+        self.root.clone().map(|root| rightmost(root).borrow().val.clone())
+    }
+    // Smallest value greater than `val`, which need not be in the tree
+    pub fn successor(&self, val: &T) -> Option<T> {
+        successor_by(&self.root, val, |val| val).map(|node| node.borrow().val.clone())
+    }
+    // Largest value less than `val`, which need not be in the tree
+    pub fn predecessor(&self, val: &T) -> Option<T> {
+        predecessor_by(&self.root, val, |val| val).map(|node| node.borrow().val.clone())
+    }
+    // Values inside `range` in increasing order. Subtrees entirely outside
+    // the range are never visited.
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Vec<T> {
+        range_by(&self.root, range, |val| val).iter().map(|node| node.borrow().val.clone()).collect()
+    }
+}
+
+// Node of an `AvlTree`: the value and the height of the subtree below it.
+pub struct AvlNode<T> {
+    pub val: T,
+    height: usize,
+}
+
+impl<T> AvlNode<T> {
+    pub fn height(&self) -> usize {
+        self.height
+    }
+}
+
+// Search tree kept height balanced with AVL rotations, so inserts, removals
+// and lookups stay O(log n).
+pub struct AvlTree<T> {
+    root: Option<TreeRef<AvlNode<T>>>,
+    len: usize,
+}
+
+impl<T> Default for AvlTree<T> {
+    fn default() -> Self {
+        Self { root: None, len: 0 }
+    }
+}
+
+impl<T: Ord + Clone> AvlTree<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn root(&self) -> Option<TreeRef<AvlNode<T>>> {
+        self.root.clone()
+    }
+
+    pub fn insert(&mut self, val: T) -> bool {
+        let Some(node) = insert_by(&mut self.root, val, |val| AvlNode { val, height: 1 }, |node| &node.val) else {
+            return false;
+        };
+        self.len += 1;
+        let parent = node.borrow().parent();
+        self.rebalance(parent);
+        true
+    }
+    pub fn contains(&self, val: &T) -> bool {
+        find_by(&self.root, val, |node| &node.val).is_some()
+    }
+    pub fn remove(&mut self, val: &T) -> bool {
+        let swap = |a: &mut AvlNode<T>, b: &mut AvlNode<T>| std::mem::swap(&mut a.val, &mut b.val);
+        let Some(parent) = remove_by(&mut self.root, val, |node| &node.val, swap) else {
+            return false;
+        };
+        self.len -= 1;
+        self.rebalance(parent);
+        true
+    }
+
+    pub fn min(&self) -> Option<T> {
+        self.root.clone().map(|root| leftmost(root).borrow().val.val.clone())
+    }
+    pub fn max(&self) -> Option<T> {
+        self.root.clone().map(|root| rightmost(root).borrow().val.val.clone())
+    }
+    pub fn successor(&self, val: &T) -> Option<T> {
+        successor_by(&self.root, val, |node| &node.val).map(|node| node.borrow().val.val.clone())
+    }
+    pub fn predecessor(&self, val: &T) -> Option<T> {
+        predecessor_by(&self.root, val, |node| &node.val).map(|node| node.borrow().val.val.clone())
+    }
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Vec<T> {
+        range_by(&self.root, range, |node| &node.val).iter().map(|node| node.borrow().val.val.clone()).collect()
+    }
+
+    // Walks from `node` up to the root fixing heights, rotating wherever the
+    // two sides differ in height by more than one.
+    fn rebalance(&mut self, mut node: Option<TreeRef<AvlNode<T>>>) {
+        while let Some(curr) = node {
+            update_height(&curr);
+            let (left, right) = {
+                let n = curr.borrow();
+                (n.left.clone(), n.right.clone())
+            };
+            let top = if avl_height(&left) > avl_height(&right) + 1 {
+                let left = left.unwrap();
+                if child_heights(&left).1 > child_heights(&left).0 {
+                    self.rotate(left, Side::Left);
+                }
+                self.rotate(curr, Side::Right)
+            } else if avl_height(&right) > avl_height(&left) + 1 {
+                let right = right.unwrap();
+                if child_heights(&right).0 > child_heights(&right).1 {
+                    self.rotate(right, Side::Right);
+                }
+                self.rotate(curr, Side::Left)
+            } else {
+                curr
+            };
+            node = top.borrow().parent();
+        }
+    }
+
+    // Rotates `node` down to `side`, lifting its child from the other side
+    // into its place. Returns the lifted child.
+    fn rotate(&mut self, node: TreeRef<AvlNode<T>>, side: Side) -> TreeRef<AvlNode<T>> {
+        let parent = node.borrow_mut().parent.take().and_then(|parent| parent.upgrade());
+        let lifted = match side {
+            Side::Left => {
+                let lifted = node.borrow_mut().right.take().expect("rotated node has a right child");
+                let inner = lifted.borrow_mut().left.take();
+                if let Some(inner) = inner {
+                    add_tree(node.clone(), inner, Side::Right);
+                }
+                add_tree(lifted.clone(), node.clone(), Side::Left);
+                lifted
+            }
+            Side::Right => {
+                let lifted = node.borrow_mut().left.take().expect("rotated node has a left child");
+                let inner = lifted.borrow_mut().right.take();
+                if let Some(inner) = inner {
+                    add_tree(node.clone(), inner, Side::Left);
+                }
+                add_tree(lifted.clone(), node.clone(), Side::Right);
+                lifted
+            }
+        };
+        replace_child(&mut self.root, parent, &node, Some(lifted.clone()));
+        update_height(&node);
+        update_height(&lifted);
+        lifted
+    }
+}
+
+fn avl_height<T>(node: &Option<TreeRef<AvlNode<T>>>) -> usize {
+    node.as_ref().map_or(0, |node| node.borrow().val.height)
+}
+
+fn child_heights<T>(node: &TreeRef<AvlNode<T>>) -> (usize, usize) {
+    let n = node.borrow();
+    (avl_height(&n.left), avl_height(&n.right))
+}
+
+fn update_height<T>(node: &TreeRef<AvlNode<T>>) {
+    let (left, right) = child_heights(node);
+    node.borrow_mut().val.height = left.max(right) + 1;
+}
+
+// Number of nodes on the longest path down from `tree`; a lone node has
+// height 1.
+pub fn height<T>(tree: TreeRef<T>) -> usize {
+    preorder(tree).map(|(_, depth)| depth + 1).max().unwrap_or(0)
+}
+
+// Whether the heights of the two subtrees of every node differ by at most
+// one, the invariant `AvlTree` maintains.
+pub fn is_balanced<T>(tree: TreeRef<T>) -> bool {
+    // Postorder finishes both subtrees right before their parent, so their
+    // heights are on top of the stack
+    let mut heights: Vec<usize> = Vec::new();
+    for (node, _) in postorder(tree) {
+        let n = node.borrow();
+        let right = if n.right.is_some() { heights.pop().unwrap() } else { 0 };
+        let left = if n.left.is_some() { heights.pop().unwrap() } else { 0 };
+        if left.abs_diff(right) > 1 {
+            return false;
+        }
+        heights.push(left.max(right) + 1);
+    }
+    true
+}
+
+// Search helpers shared by the trees above. `key` picks the ordered value
+// out of whatever the nodes hold.
+
+fn find_by<N, T: Ord>(root: &Option<TreeRef<N>>, val: &T, key: fn(&N) -> &T) -> Option<TreeRef<N>> {
+    let mut curr = root.clone();
+    while let Some(node) = curr {
+        let ordering = val.cmp(key(&node.borrow().val));
+        let next = match ordering {
+            std::cmp::Ordering::Equal => return Some(node),
+            std::cmp::Ordering::Less => node.borrow().left.clone(),
+            std::cmp::Ordering::Greater => node.borrow().right.clone(),
+        };
+        curr = next;
+    }
+    None
+}
+
+// Adds a leaf for `val` and returns it, or None if `val` is already present.
+fn insert_by<N, T: Ord>(root: &mut Option<TreeRef<N>>, val: T, make: impl FnOnce(T) -> N, key: fn(&N) -> &T) -> Option<TreeRef<N>> {
+    let Some(mut curr) = root.clone() else {
+        let node = BinTree::as_ref(make(val));
+        *root = Some(node.clone());
+        return Some(node);
+    };
+    loop {
+        let (next, side) = {
+            let node = curr.borrow();
+            match val.cmp(key(&node.val)) {
+                std::cmp::Ordering::Equal => return None,
+                std::cmp::Ordering::Less => (node.left.clone(), Side::Left),
+                std::cmp::Ordering::Greater => (node.right.clone(), Side::Right),
+            }
+        };
+        match next {
+            Some(next) => curr = next,
+            None => {
+                let node = BinTree::as_ref(make(val));
+                add_tree(curr, node.clone(), side);
+                return Some(node);
+            }
+        }
+    }
+}
+
+// Unlinks the node holding `val`. Returns None if there is none, otherwise
+// the parent of the node that was taken out of the tree.
+fn remove_by<N, T: Ord>(root: &mut Option<TreeRef<N>>, val: &T, key: fn(&N) -> &T, swap: impl FnOnce(&mut N, &mut N)) -> Option<Option<TreeRef<N>>> {
+    let mut node = find_by(root, val, key)?;
+    // A node with two children takes its successor's value, and the
+    // successor, which has no left child, is unlinked instead
+    let (has_left, right) = {
+        let curr = node.borrow();
+        (curr.left.is_some(), curr.right.clone())
+    };
+    if let (true, Some(right)) = (has_left, right) {
+        let successor = leftmost(right);
+        swap(&mut node.borrow_mut().val, &mut successor.borrow_mut().val);
+        node = successor;
+    }
+    let child = {
+        let curr = node.borrow();
+        curr.left.clone().or_else(|| curr.right.clone())
+    };
+    let parent = node.borrow_mut().parent.take().and_then(|parent| parent.upgrade());
+    replace_child(root, parent.clone(), &node, child);
+    Some(parent)
+}
+
+// Points whatever referenced `old`, its parent or the root, at `new`.
+fn replace_child<N>(root: &mut Option<TreeRef<N>>, parent: Option<TreeRef<N>>, old: &TreeRef<N>, new: Option<TreeRef<N>>) {
+    if let Some(new) = &new {
+        new.borrow_mut().parent = parent.as_ref().map(Rc::downgrade);
+    }
+    match parent {
+        None => *root = new,
+        Some(parent) => {
+            let mut parent = parent.borrow_mut();
+            if parent.left.as_ref().is_some_and(|left| Rc::ptr_eq(left, old)) {
+                parent.left = new;
+            } else {
+                parent.right = new;
+            }
+        }
+    }
+}
+
+fn successor_by<N, T: Ord>(root: &Option<TreeRef<N>>, val: &T, key: fn(&N) -> &T) -> Option<TreeRef<N>> {
+    let mut best = None;
+    let mut curr = root.clone();
+    while let Some(node) = curr {
+        let next = if key(&node.borrow().val) > val {
+            let left = node.borrow().left.clone();
+            best = Some(node);
+            left
+        } else {
+            node.borrow().right.clone()
+        };
+        curr = next;
+    }
+    best
+}
+
+fn predecessor_by<N, T: Ord>(root: &Option<TreeRef<N>>, val: &T, key: fn(&N) -> &T) -> Option<TreeRef<N>> {
+    let mut best = None;
+    let mut curr = root.clone();
+    while let Some(node) = curr {
+        let next = if key(&node.borrow().val) < val {
+            let right = node.borrow().right.clone();
+            best = Some(node);
+            right
+        } else {
+            node.borrow().left.clone()
+        };
+        curr = next;
+    }
+    best
+}
+
+fn range_by<N, T: Ord, R: RangeBounds<T>>(root: &Option<TreeRef<N>>, range: R, key: fn(&N) -> &T) -> Vec<TreeRef<N>> {
+    let after_start = |val: &T| match range.start_bound() {
+        Bound::Included(start) => val >= start,
+        Bound::Excluded(start) => val > start,
+        Bound::Unbounded => true,
+    };
+    let past_end = |val: &T| match range.end_bound() {
+        Bound::Included(end) => val > end,
+        Bound::Excluded(end) => val >= end,
+        Bound::Unbounded => false,
+    };
+    let mut out = Vec::new();
+    let mut stack = Vec::new();
+    let mut curr = root.clone();
+    loop {
+        while let Some(node) = curr {
+            // Below the start only the right subtree can be in range
+            let (keep, next) = {
+                let n = node.borrow();
+                let keep = after_start(key(&n.val));
+                (keep, if keep { n.left.clone() } else { n.right.clone() })
+            };
+            if keep {
+                stack.push(node);
+            }
+            curr = next;
+        }
+        let Some(node) = stack.pop() else {
+            break;
+        };
+        if past_end(key(&node.borrow().val)) {
             break;
         }
+        curr = node.borrow().right.clone();
+        out.push(node);
+    }
+    out
+}
+
+fn leftmost<T>(mut node: TreeRef<T>) -> TreeRef<T> {
+    loop {
+        let next = node.borrow().left.clone();
+        match next {
+            Some(next) => node = next,
+            None => return node,
+        }
+    }
+}
+
+fn rightmost<T>(mut node: TreeRef<T>) -> TreeRef<T> {
+    loop {
+        let next = node.borrow().right.clone();
+        match next {
+            Some(next) => node = next,
+            None => return node,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(order: impl Iterator<Item = (Rc<RefCell<BinTree<i32>>>, usize)>) -> Vec<(i32, usize)> {
+        order.map(|(node, depth)| (node.borrow().val, depth)).collect()
+    }
+
+    fn vals(order: impl Iterator<Item = (Rc<RefCell<BinTree<i32>>>, usize)>) -> Vec<i32> {
+        values(order).into_iter().map(|(val, _)| val).collect()
+    }
+
+    fn rebuilds(inorder_vals: Vec<i32>, preorder_vals: Vec<i32>) {
+        let tree = create_from_orders(inorder_vals.clone(), preorder_vals.clone()).unwrap();
+        assert_eq!(vals(inorder(tree.clone())), inorder_vals);
+        assert_eq!(vals(preorder(tree)), preorder_vals);
+    }
+
+    #[test]
+    fn add_element_sets_children_and_parent() {
+        let root = BinTree::as_ref(0);
+        add_element(root.clone(), 4, Side::Right);
+        add_element(root.clone(), 5, Side::Left);
+        assert_eq!(root.borrow().left.as_ref().unwrap().borrow().val, 5);
+        assert_eq!(root.borrow().right.as_ref().unwrap().borrow().val, 4);
+        assert!(has_parent(root.borrow().left.clone().unwrap()));
+        assert!(!has_parent(root.clone()));
+        assert_eq!(get_size(root), 3);
+    }
+
+    #[test]
+    fn add_tree_grafts_a_whole_subtree() {
+        let sub = BinTree::as_ref(1);
+        add_element(sub.clone(), 2, Side::Left);
+        let root = BinTree::as_ref(0);
+        add_tree(root.clone(), sub, Side::Right);
+        assert_eq!(get_size(root.clone()), 3);
+        assert_eq!(vals(preorder(root)), [0, 1, 2]);
+    }
+
+    #[test]
+    fn traversals_visit_in_order_with_depths() {
+        //       1
+        //     2   3
+        //    4     5
+        //         6
+        let tree = create_from_orders(vec![4, 2, 1, 3, 6, 5], vec![1, 2, 4, 3, 5, 6]).unwrap();
+        assert_eq!(values(preorder(tree.clone())), [(1, 0), (2, 1), (4, 2), (3, 1), (5, 2), (6, 3)]);
+        assert_eq!(values(inorder(tree.clone())), [(4, 2), (2, 1), (1, 0), (3, 1), (6, 3), (5, 2)]);
+        assert_eq!(values(postorder(tree.clone())), [(4, 2), (2, 1), (6, 3), (5, 2), (3, 1), (1, 0)]);
+        assert_eq!(values(level_order(tree)), [(1, 0), (2, 1), (3, 1), (4, 2), (5, 2), (6, 3)]);
+    }
+
+    #[test]
+    fn traversals_of_a_single_node() {
+        let tree = BinTree::as_ref(9);
+        assert_eq!(values(preorder(tree.clone())), [(9, 0)]);
+        assert_eq!(values(inorder(tree.clone())), [(9, 0)]);
+        assert_eq!(values(postorder(tree.clone())), [(9, 0)]);
+        assert_eq!(values(level_order(tree)), [(9, 0)]);
+    }
+
+    #[test]
+    fn traversals_handle_deep_trees() {
+        let root = BinTree::as_ref(0);
+        let mut tip = root.clone();
+        for i in 1..100_000 {
+            add_element(tip.clone(), i, Side::Right);
+            let next = tip.borrow().right.clone().unwrap();
+            tip = next;
+        }
+        assert_eq!(get_size(root.clone()), 100_000);
+        assert_eq!(postorder(root.clone()).next().unwrap().1, 99_999);
+        assert_eq!(inorder(root).last().unwrap().1, 99_999);
+    }
+
+    #[test]
+    fn create_from_orders_rebuilds_balanced_tree() {
+        rebuilds(vec![4, 2, 5, 1, 6, 3, 7], vec![1, 2, 4, 5, 3, 6, 7]);
+    }
+
+    #[test]
+    fn create_from_orders_rebuilds_one_sided_trees() {
+        rebuilds(vec![3, 2, 1], vec![1, 2, 3]);
+        rebuilds(vec![1, 2, 3], vec![1, 2, 3]);
+        rebuilds(vec![2, 3, 1], vec![1, 2, 3]);
+        rebuilds(vec![7], vec![7]);
+    }
+
+    #[test]
+    fn create_from_orders_rejects_mismatched_orders() {
+        assert!(create_from_orders(Vec::<i32>::new(), Vec::new()).is_err());
+        assert!(create_from_orders(vec![1, 2], vec![1]).is_err());
+        assert!(create_from_orders(vec![1, 2, 3], vec![1, 2, 4]).is_err());
+        assert!(create_from_orders(vec![2, 1, 3], vec![4, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn create_from_postorder_and_level_order_match_preorder() {
+        let cases = [
+            (vec![4, 2, 5, 1, 6, 3, 7], vec![1, 2, 4, 5, 3, 6, 7]),
+            (vec![3, 2, 1], vec![1, 2, 3]),
+            (vec![1, 2, 3], vec![1, 2, 3]),
+            (vec![2, 3, 1], vec![1, 2, 3]),
+            (vec![4, 2, 1, 3, 6, 5], vec![1, 2, 4, 3, 5, 6]),
+            (vec![7], vec![7]),
+        ];
+        for (inorder_vals, preorder_vals) in cases {
+            let tree = create_from_orders(inorder_vals.clone(), preorder_vals).unwrap();
+            let from_post = create_from_postorder(inorder_vals.clone(), vals(postorder(tree.clone()))).unwrap();
+            let from_level = create_from_level_order(inorder_vals, vals(level_order(tree.clone()))).unwrap();
+            assert!(from_post.borrow().deep_eq(&tree.borrow()));
+            assert!(from_level.borrow().deep_eq(&tree.borrow()));
+        }
+    }
+
+    #[test]
+    fn mismatched_postorder_and_level_order_are_errors() {
+        assert!(create_from_postorder(vec![1, 2], vec![1]).is_err());
+        assert!(create_from_postorder(vec![2, 1, 3], vec![2, 4, 1]).is_err());
+        assert!(create_from_level_order(Vec::<i32>::new(), Vec::new()).is_err());
+        assert!(create_from_level_order(vec![2, 1, 3], vec![1, 2, 4]).is_err());
+    }
+
+    #[test]
+    fn rebuilds_deep_trees() {
+        // Chains leaning each way, and a zigzag
+        let n = 100_000;
+        let up: Vec<i32> = (0..n).collect();
+        let down: Vec<i32> = (0..n).rev().collect();
+        let zigzag: Vec<i32> = (0..n).map(|i| if i % 2 == 0 { i / 2 } else { n - 1 - i / 2 }).collect();
+        for preorder_vals in [up.clone(), down, zigzag] {
+            let tree = create_from_orders(up.clone(), preorder_vals.clone()).unwrap();
+            assert_eq!(vals(preorder(tree.clone())), preorder_vals);
+            let from_post = create_from_postorder(up.clone(), vals(postorder(tree.clone()))).unwrap();
+            let from_level = create_from_level_order(up.clone(), vals(level_order(tree.clone()))).unwrap();
+            assert!(from_post.borrow().deep_eq(&tree.borrow()));
+            assert!(from_level.borrow().deep_eq(&tree.borrow()));
+        }
+    }
+
+    #[test]
+    fn repeated_values_and_misordered_levels_are_errors() {
+        assert!(create_from_orders(vec![1, 1], vec![1, 1]).is_err());
+        assert!(create_from_level_order(vec![1, 2, 1], vec![2, 1, 1]).is_err());
+        //     2
+        //   1   4
+        //      3
+        assert!(create_from_level_order(vec![1, 2, 3, 4], vec![2, 1, 4, 3]).is_ok());
+        assert!(create_from_level_order(vec![1, 2, 3, 4], vec![2, 4, 3, 1]).is_err());
+    }
+
+    #[test]
+    fn serialize_round_trips_repeated_values() {
+        // Same values everywhere, so only the markers tell the shapes apart
+        let left_heavy = deserialize(&[Some(0), Some(0), Some(0), None, None, None, None]).unwrap();
+        let right_heavy = deserialize(&[Some(0), None, Some(0), None, Some(0), None, None]).unwrap();
+        assert!(!left_heavy.borrow().deep_eq(&right_heavy.borrow()));
+        for tree in [left_heavy, right_heavy, create_from_orders(vec![4, 2, 5, 1, 6, 3, 7], vec![1, 2, 4, 5, 3, 6, 7]).unwrap()] {
+            let items = serialize(tree.clone());
+            assert_eq!(items.iter().filter(|item| item.is_none()).count(), items.len() / 2 + 1);
+            assert!(deserialize(&items).unwrap().borrow().deep_eq(&tree.borrow()));
+        }
+    }
+
+    #[test]
+    fn malformed_serialized_trees_are_errors() {
+        assert!(deserialize::<i32>(&[]).is_err());
+        assert!(deserialize::<i32>(&[None]).is_err());
+        assert!(deserialize(&[Some(1), None]).is_err());
+        assert!(deserialize(&[Some(1), None, None, None]).is_err());
+        assert!(deserialize(&[Some(1), Some(2), None, None]).is_err());
+    }
+
+    fn search_tree(vals: &[i32]) -> SearchTree<i32> {
+        let mut tree = SearchTree::new();
+        for &val in vals {
+            tree.insert(val);
+        }
+        tree
+    }
+
+    // Inorder is sorted and every child points back at its parent
+    fn assert_valid(tree: &SearchTree<i32>) {
+        let Some(root) = tree.root() else {
+            assert!(tree.is_empty());
+            return;
+        };
+        assert!(root.borrow().parent().is_none());
+        let sorted = vals(inorder(root.clone()));
+        assert!(sorted.windows(2).all(|w| w[0] < w[1]), "{:?}", sorted);
+        assert_eq!(sorted.len(), tree.len());
+        for (node, _) in preorder(root) {
+            let n = node.borrow();
+            for child in [&n.left, &n.right].into_iter().flatten() {
+                assert!(Rc::ptr_eq(&child.borrow().parent().unwrap(), &node));
+            }
+        }
+    }
+
+    #[test]
+    fn search_tree_inserts_and_finds() {
+        let mut tree = search_tree(&[50, 30, 70, 20, 40, 60, 80]);
+        assert!(!tree.insert(40));
+        assert_eq!(tree.len(), 7);
+        assert!(tree.contains(&60));
+        assert!(!tree.contains(&65));
+        assert_eq!(tree.min(), Some(20));
+        assert_eq!(tree.max(), Some(80));
+        assert_valid(&tree);
+        assert_eq!(SearchTree::<i32>::new().min(), None);
+    }
+
+    #[test]
+    fn search_tree_removes_every_kind_of_node() {
+        let mut tree = search_tree(&[50, 30, 70, 20, 40, 60, 80, 65]);
+        assert!(tree.remove(&20));
+        assert!(tree.remove(&60));
+        assert!(tree.remove(&30));
+        assert!(tree.remove(&50));
+        assert!(!tree.remove(&50));
+        assert_valid(&tree);
+        assert_eq!(vals(inorder(tree.root().unwrap())), [40, 65, 70, 80]);
+        for val in [40, 65, 70, 80] {
+            assert!(tree.remove(&val));
+            assert_valid(&tree);
+        }
+        assert!(tree.is_empty());
+        assert!(tree.root().is_none());
+    }
+
+    #[test]
+    fn search_tree_neighbours_and_ranges() {
+        let tree = search_tree(&[50, 30, 70, 20, 40, 60, 80]);
+        assert_eq!(tree.successor(&40), Some(50));
+        assert_eq!(tree.successor(&45), Some(50));
+        assert_eq!(tree.successor(&80), None);
+        assert_eq!(tree.predecessor(&60), Some(50));
+        assert_eq!(tree.predecessor(&20), None);
+        assert_eq!(tree.range(30..60), [30, 40, 50]);
+        assert_eq!(tree.range(30..=60), [30, 40, 50, 60]);
+        assert_eq!(tree.range(..35), [20, 30]);
+        assert_eq!(tree.range(75..), [80]);
+        assert_eq!(tree.range((Bound::Excluded(20), Bound::Excluded(40))), [30]);
+        assert!(tree.range(41..50).is_empty());
+    }
+
+    #[test]
+    fn search_tree_matches_btreeset() {
+        let mut tree = SearchTree::new();
+        let mut set = std::collections::BTreeSet::new();
+        let mut seed = 12345u32;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let val = (seed >> 16) as i32 % 200;
+            if seed & 1 == 0 {
+                assert_eq!(tree.insert(val), set.insert(val));
+            } else {
+                assert_eq!(tree.remove(&val), set.remove(&val));
+            }
+        }
+        assert_valid(&tree);
+        assert_eq!(tree.range(..), set.iter().copied().collect::<Vec<_>>());
+        assert_eq!(tree.min(), set.first().copied());
+        assert_eq!(tree.successor(&100), set.range(101..).next().copied());
     }
-    if split_option.is_none(){
-        panic!("I don't feel like making a result return, the trees don't match");
+
+    fn assert_valid_avl(tree: &AvlTree<i32>) {
+        let Some(root) = tree.root() else {
+            assert!(tree.is_empty());
+            return;
+        };
+        assert!(root.borrow().parent().is_none());
+        assert!(is_balanced(root.clone()));
+        let sorted: Vec<i32> = inorder(root.clone()).map(|(node, _)| node.borrow().val.val).collect();
+        assert!(sorted.windows(2).all(|w| w[0] < w[1]), "{:?}", sorted);
+        assert_eq!(sorted.len(), tree.len());
+        for (node, _) in preorder(root) {
+            let n = node.borrow();
+            assert_eq!(n.val.height(), height(node.clone()));
+            for child in [&n.left, &n.right].into_iter().flatten() {
+                assert!(Rc::ptr_eq(&child.borrow().parent().unwrap(), &node));
+            }
+        }
     }
-    println!("{}", split_option.unwrap());
-    let in_split = split_option.unwrap();
-    let pre_split_option = preorder.iter().position(|&r| r == inorder[in_split-1]);
-    if pre_split_option.is_none() {
-        panic!("i expect thjis");
+
+    #[test]
+    fn height_and_balance_of_plain_trees() {
+        let balanced = create_from_orders(vec![4, 2, 5, 1, 6, 3, 7], vec![1, 2, 4, 5, 3, 6, 7]).unwrap();
+        assert_eq!(height(balanced.clone()), 3);
+        assert!(is_balanced(balanced));
+        let chain = create_from_orders(vec![3, 2, 1], vec![1, 2, 3]).unwrap();
+        assert_eq!(height(chain.clone()), 3);
+        assert!(!is_balanced(chain));
+        assert_eq!(height(BinTree::as_ref(1)), 1);
     }
-    let pre_split = pre_split_option.unwrap() + 1;
-    let left = create_from_orders(inorder[0..in_split].to_vec(), preorder[0..pre_split].to_vec());
-    let right = create_from_orders(inorder[in_split+1..].to_vec(), preorder[pre_split..].to_vec());
 
-    
+    #[test]
+    fn avl_tree_stays_balanced_on_sorted_inserts() {
+        let mut tree = AvlTree::new();
+        let mut plain = SearchTree::new();
+        for val in 0..1024 {
+            assert!(tree.insert(val));
+            plain.insert(val);
+        }
+        assert_valid_avl(&tree);
+        // A perfect tree of 1024 nodes has height 11; AVL allows ~1.44x
+        assert!(height(tree.root().unwrap()) <= 15);
+        assert_eq!(height(plain.root().unwrap()), 1024);
+        assert_eq!(tree.range(500..505), [500, 501, 502, 503, 504]);
+        assert_eq!((tree.min(), tree.max()), (Some(0), Some(1023)));
+        assert_eq!((tree.successor(&10), tree.predecessor(&10)), (Some(11), Some(9)));
+    }
 
-    // println!("left 0..{}", in_split);
-    // println!("left = {}", left.borrow_mut().val);
-    // println!("right {}..", in_split+1);
-    // println!("right = {}", right.borrow_mut().val);
+    #[test]
+    fn avl_tree_matches_btreeset() {
+        let mut tree = AvlTree::new();
+        let mut set = std::collections::BTreeSet::new();
+        let mut seed = 777u32;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let val = (seed >> 16) as i32 % 300;
+            if !seed.is_multiple_of(3) {
+                assert_eq!(tree.insert(val), set.insert(val));
+            } else {
+                assert_eq!(tree.remove(&val), set.remove(&val));
+            }
+            assert_eq!(tree.contains(&val), set.contains(&val));
+        }
+        assert_valid_avl(&tree);
+        assert_eq!(tree.range(..), set.iter().copied().collect::<Vec<_>>());
+        for val in set.clone() {
+            assert!(tree.remove(&val));
+        }
+        assert_valid_avl(&tree);
+        assert!(tree.root().is_none());
+    }
 
+    #[test]
+    fn map_and_fold() {
+        let tree = create_from_orders(vec![4, 2, 5, 1, 6, 3, 7], vec![1, 2, 4, 5, 3, 6, 7]).unwrap();
+        let doubled = tree.borrow().map(|val| val * 2);
+        assert_eq!(vals(preorder(doubled.clone())), [2, 4, 8, 10, 6, 12, 14]);
+        assert!(doubled.borrow().same_shape(&tree.borrow()));
+        let names = tree.borrow().map(|val| val.to_string());
+        assert_eq!(names.borrow().left.as_ref().unwrap().borrow().val, "2");
+        assert!(Rc::ptr_eq(&names.borrow().left.as_ref().unwrap().borrow().parent().unwrap(), &names));
 
-    add_tree(curr_node.clone(), left, Side::Left);
-    add_tree(curr_node.clone(), right, Side::Right);
-    return curr_node;
-}
\ No newline at end of file
+        let sum = tree.borrow().fold(0, |val, left, right| val + left + right);
+        assert_eq!(sum, 28);
+        let tree_height = tree.borrow().fold(0, |_, left: usize, right| left.max(right) + 1);
+        assert_eq!(tree_height, height(tree.clone()));
+        // Inorder listing rebuilt bottom up
+        let listed = tree.borrow().fold(Vec::new(), |val, mut left, right| {
+            left.push(*val);
+            left.extend(right);
+            left
+        });
+        assert_eq!(listed, vals(inorder(tree)));
+    }
+
+    #[test]
+    fn zip_with_needs_the_same_shape() {
+        let a = create_from_orders(vec![2, 1, 3], vec![1, 2, 3]).unwrap();
+        let b = a.borrow().map(|val| val * 10);
+        let zipped = a.borrow().zip_with(&b.borrow(), |x, y| x + y).unwrap();
+        assert_eq!(vals(preorder(zipped)), [11, 22, 33]);
+        let c = create_from_orders(vec![1, 2, 3], vec![1, 2, 3]).unwrap();
+        assert!(a.borrow().zip_with(&c.borrow(), |x, y| x + y).is_none());
+    }
+
+    #[test]
+    fn clones_are_deep() {
+        let tree = create_from_orders(vec![2, 1, 3], vec![1, 2, 3]).unwrap();
+        let linked = deep_clone(&tree);
+        assert!(linked.borrow().deep_eq(&tree.borrow()));
+        linked.borrow().left.as_ref().unwrap().borrow_mut().val = 9;
+        assert_eq!(tree.borrow().left.as_ref().unwrap().borrow().val, 2);
+        let copy = tree.borrow().clone();
+        assert!(copy.deep_eq(&tree.borrow()));
+        assert!(copy.left.as_ref().unwrap().borrow().parent.is_none());
+    }
+
+    // Counts how many values have been dropped
+    struct Tracked(Rc<RefCell<usize>>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            *self.0.borrow_mut() += 1;
+        }
+    }
+
+    #[test]
+    fn trees_are_freed_with_their_root() {
+        let dropped = Rc::new(RefCell::new(0));
+        let root = BinTree::as_ref(Tracked(dropped.clone()));
+        add_element(root.clone(), Tracked(dropped.clone()), Side::Left);
+        add_element(root.clone(), Tracked(dropped.clone()), Side::Right);
+        let leaf = root.borrow().left.clone().unwrap();
+        add_element(leaf.clone(), Tracked(dropped.clone()), Side::Right);
+        let (weak_root, weak_leaf) = (Rc::downgrade(&root), Rc::downgrade(&leaf));
+        drop(leaf);
+        drop(root);
+        assert!(weak_root.upgrade().is_none());
+        assert!(weak_leaf.upgrade().is_none());
+        assert_eq!(*dropped.borrow(), 4);
+    }
+
+    #[test]
+    fn held_subtrees_outlive_their_parent() {
+        let root = BinTree::as_ref(1);
+        add_element(root.clone(), 2, Side::Left);
+        let child = root.borrow().left.clone().unwrap();
+        drop(root);
+        assert_eq!(child.borrow().val, 2);
+        assert!(child.borrow().parent().is_none());
+        assert!(!has_parent(child));
+    }
+
+    #[test]
+    fn ancestors_and_path_to_root() {
+        let tree = create_from_orders(vec![4, 2, 1, 3, 6, 5], vec![1, 2, 4, 3, 5, 6]).unwrap();
+        let six = preorder(tree.clone()).find(|(node, _)| node.borrow().val == 6).unwrap().0;
+        let up: Vec<i32> = ancestors(six.clone()).map(|node| node.borrow().val).collect();
+        assert_eq!(up, [5, 3, 1]);
+        assert_eq!(path_to_root(six), [Side::Right, Side::Right, Side::Left]);
+        assert!(path_to_root(tree.clone()).is_empty());
+        assert_eq!(ancestors(tree).count(), 0);
+    }
+
+    #[test]
+    fn deep_eq_compares_shape_and_eq_compares_values() {
+        let a = create_from_orders(vec![2, 1, 3], vec![1, 2, 3]).unwrap();
+        let b = create_from_orders(vec![2, 1, 3], vec![1, 2, 3]).unwrap();
+        let c = create_from_orders(vec![1, 2, 3], vec![1, 2, 3]).unwrap();
+        assert!(a.borrow().deep_eq(&b.borrow()));
+        assert!(!a.borrow().deep_eq(&c.borrow()));
+        // Same root value, so equal and ordered equal despite the shapes
+        assert!(*a.borrow() == *c.borrow());
+        assert_eq!(a.borrow().cmp(&c.borrow()), std::cmp::Ordering::Equal);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_uses_the_null_marker_layout() {
+        let tree = deserialize(&[Some(2), Some(1), None, None, Some(3), None, None]).unwrap();
+        let json = serde_json::to_string(&*tree.borrow()).unwrap();
+        assert_eq!(json, "[2,1,null,null,3,null,null]");
+        let back: BinTree<i32> = serde_json::from_str(&json).unwrap();
+        assert!(back.deep_eq(&tree.borrow()));
+        assert!(back.left.as_ref().unwrap().borrow().parent().is_none());
+        assert!(serde_json::from_str::<BinTree<i32>>("[2,1,null]").is_err());
+        assert!(serde_json::from_str::<BinTree<i32>>("[null]").is_err());
+    }
+}
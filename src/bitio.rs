@@ -0,0 +1,116 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Read};
+
+// Bits are packed least significant first, the same order
+// `HuffmanState::compress` has always used.
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bit: u32,
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn push_bit(&mut self, flag: bool) {
+        if self.bit == 0 {
+            self.bytes.push(0);
+        }
+        if flag {
+            *self.bytes.last_mut().unwrap() |= 1 << self.bit;
+        }
+        self.bit = (self.bit + 1) % 8;
+    }
+    pub fn push_bits(&mut self, value: u64, count: u32) {
+        for i in 0..count {
+            self.push_bit((value >> i) & 1 == 1);
+        }
+    }
+    // Writes the `len` low bits of `code` most significant first, which is
+    // the order a canonical Huffman decoder walks them.
+    pub fn push_code(&mut self, code: u32, len: u32) {
+        for i in (0..len).rev() {
+            self.push_bit((code >> i) & 1 == 1);
+        }
+    }
+    pub fn bit_len(&self) -> u64 {
+        let full = self.bytes.len() as u64 * 8;
+        if self.bit == 0 {
+            full
+        } else {
+            full - 8 + self.bit as u64
+        }
+    }
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+    pub fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.pos / 8)?;
+        let flag = byte & (1 << (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(flag)
+    }
+    pub fn read_bits(&mut self, count: u32) -> Option<u64> {
+        let mut value = 0;
+        for i in 0..count {
+            if self.read_bit()? {
+                value |= 1 << i;
+            }
+        }
+        Some(value)
+    }
+    pub fn bits_left(&self) -> usize {
+        (self.bytes.len() * 8).saturating_sub(self.pos)
+    }
+}
+
+// LEB128: seven bits per byte, low bits first, high bit set on all but
+// the last byte.
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub fn read_varint(input: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *input.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(feature = "std")]
+pub fn read_varint_from<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        input.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7F) as u64).checked_shl(shift)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "varint too long"))?;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "varint too long"))
+}
@@ -0,0 +1,112 @@
+use std::io;
+
+// Returns the last column of the sorted rotation matrix of `block` and the
+// row at which the unrotated block ended up.
+pub fn forward(block: &[u8]) -> (Vec<u8>, usize) {
+    let n = block.len();
+    let mut last = Vec::with_capacity(n);
+    let mut primary = 0;
+    for (row, &start) in rotation_array(block).iter().enumerate() {
+        if start == 0 {
+            primary = row;
+        }
+        last.push(block[(start + n - 1) % n]);
+    }
+    (last, primary)
+}
+
+pub fn inverse(last: &[u8], primary: usize) -> io::Result<Vec<u8>> {
+    let n = last.len();
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    if primary >= n {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bwt primary index out of range"));
+    }
+    let mut starts = [0usize; 256];
+    for &c in last {
+        starts[c as usize] += 1;
+    }
+    let mut total = 0;
+    for start in starts.iter_mut() {
+        let count = *start;
+        *start = total;
+        total += count;
+    }
+    // lf[i] is the row whose rotation starts one byte before row i's
+    let mut lf = vec![0usize; n];
+    for (i, &c) in last.iter().enumerate() {
+        lf[i] = starts[c as usize];
+        starts[c as usize] += 1;
+    }
+    let mut data = vec![0u8; n];
+    let mut row = primary;
+    for i in (0..n).rev() {
+        data[i] = last[row];
+        row = lf[row];
+    }
+    Ok(data)
+}
+
+// Suffix array of the block read cyclically, built by prefix doubling: after
+// the round with step k every rotation is ranked by its first 2k bytes.
+fn rotation_array(block: &[u8]) -> Vec<usize> {
+    let n = block.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    let mut rank: Vec<usize> = block.iter().map(|&c| c as usize).collect();
+    let mut next_rank = vec![0usize; n];
+    let mut k = 1;
+    if n < 2 {
+        return order;
+    }
+    loop {
+        {
+            let key = |i: usize| (rank[i], rank[(i + k) % n]);
+            order.sort_unstable_by_key(|&i| key(i));
+            next_rank[order[0]] = 0;
+            for w in 1..n {
+                let step = usize::from(key(order[w - 1]) != key(order[w]));
+                next_rank[order[w]] = next_rank[order[w - 1]] + step;
+            }
+        }
+        std::mem::swap(&mut rank, &mut next_rank);
+        // Either every rotation is distinct or the block is periodic and the
+        // remaining ties are between identical rotations.
+        if rank[order[n - 1]] == n - 1 || k >= n {
+            break;
+        }
+        k *= 2;
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(block: &[u8]) {
+        let (last, primary) = forward(block);
+        assert_eq!(inverse(&last, primary).unwrap(), block);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(b"");
+        round_trip(b"x");
+        round_trip(&[9; 100]);
+        round_trip(b"abababab");
+        round_trip(b"mississippi banana");
+        round_trip(&(0..=255).rev().collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn sorts_the_rotations() {
+        assert_eq!(forward(b"banana"), (b"nnbaaa".to_vec(), 3));
+    }
+
+    #[test]
+    fn rejects_a_primary_index_past_the_end() {
+        let (last, _) = forward(b"banana");
+        assert_eq!(inverse(&last, 6).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,284 @@
+use std::io;
+
+use crate::bitio::{BitReader, BitWriter};
+use crate::huffman::{self, CanonicalDecoder};
+use crate::{bwt, mtf};
+
+// bzip2-style block compression: each block is sorted with the
+// Burrows-Wheeler transform, move-to-front coded, has its zero runs
+// collapsed and is finally Huffman coded with several tables, one of which
+// is selected for every group of GROUP_SIZE symbols.
+pub const BLOCK_SIZE: usize = 900_000;
+const GROUP_SIZE: usize = 50;
+const MAX_TABLES: usize = 6;
+const MAX_CODE_LENGTH: u32 = 17;
+const SELECTION_ROUNDS: usize = 4;
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    compress_with_block_size(data, BLOCK_SIZE).expect("default block size is valid")
+}
+
+// Blocks can be no larger than BLOCK_SIZE, the most `decompress` accepts.
+pub fn compress_with_block_size(data: &[u8], block_size: usize) -> io::Result<Vec<u8>> {
+    if block_size == 0 || block_size > BLOCK_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "bzip block size must be between 1 and BLOCK_SIZE"));
+    }
+    let mut writer = BitWriter::new();
+    for block in data.chunks(block_size) {
+        writer.push_bit(true);
+        write_block(&mut writer, block);
+    }
+    writer.push_bit(false);
+    Ok(writer.finish())
+}
+
+// Fails before decoding a block that would take the output past `max_len`
+// bytes, so untrusted input should come with a limit.
+pub fn decompress(compressed: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut reader = BitReader::new(compressed);
+    let mut data = Vec::new();
+    while read_bit(&mut reader)? {
+        read_block(&mut reader, &mut data, max_len)?;
+    }
+    Ok(data)
+}
+
+fn write_block(writer: &mut BitWriter, block: &[u8]) {
+    let (last, primary) = bwt::forward(block);
+    let symbols = mtf::encode_zero_runs(&mtf::move_to_front(&last));
+    let (tables, selectors) = select_tables(&symbols);
+
+    writer.push_bits(block.len() as u64, 32);
+    writer.push_bits(primary as u64, 32);
+    writer.push_bits(tables.len() as u64, 3);
+    for lengths in &tables {
+        huffman::write_code_lengths(writer, lengths);
+    }
+    writer.push_bits(selectors.len() as u64, 32);
+    let mut order: Vec<usize> = (0..tables.len()).collect();
+    for &selector in &selectors {
+        // Selectors are move-to-front coded and written in unary
+        let index = order.iter().position(|&t| t == selector).unwrap();
+        order.remove(index);
+        order.insert(0, selector);
+        for _ in 0..index {
+            writer.push_bit(true);
+        }
+        writer.push_bit(false);
+    }
+
+    let codes: Vec<Vec<u32>> = tables.iter().map(|lengths| huffman::canonical_codes(lengths)).collect();
+    for (group, &table) in symbols.chunks(GROUP_SIZE).zip(&selectors) {
+        for &symbol in group {
+            writer.push_code(codes[table][symbol as usize], tables[table][symbol as usize]);
+        }
+    }
+}
+
+fn read_block(reader: &mut BitReader, data: &mut Vec<u8>, max_len: usize) -> io::Result<()> {
+    let len = read_bits(reader, 32)? as usize;
+    let primary = read_bits(reader, 32)? as usize;
+    // Checked before anything is decoded, as the length sizes every buffer
+    if len > BLOCK_SIZE {
+        return Err(invalid("block longer than the block size"));
+    }
+    if len > max_len - data.len() {
+        return Err(invalid("bzip data too large"));
+    }
+    if primary >= len {
+        return Err(invalid("bwt primary index out of range"));
+    }
+    let table_count = read_bits(reader, 3)? as usize;
+    if !(2..=MAX_TABLES).contains(&table_count) {
+        return Err(invalid("bad huffman table count"));
+    }
+    let mut decoders = Vec::with_capacity(table_count);
+    for _ in 0..table_count {
+        let lengths = huffman::read_code_lengths(reader, mtf::ALPHABET_SIZE, MAX_CODE_LENGTH)?;
+        decoders.push(CanonicalDecoder::new(&lengths));
+    }
+    let selector_count = read_bits(reader, 32)? as usize;
+    // Every selector costs at least one bit, which bounds the allocation
+    if selector_count == 0 || selector_count > reader.bits_left() {
+        return Err(invalid("bad selector count"));
+    }
+    let mut order: Vec<usize> = (0..table_count).collect();
+    let mut selectors = Vec::with_capacity(selector_count);
+    for _ in 0..selector_count {
+        let mut index = 0;
+        while read_bit(reader)? {
+            index += 1;
+            if index >= table_count {
+                return Err(invalid("bad selector"));
+            }
+        }
+        let table = order.remove(index);
+        order.insert(0, table);
+        selectors.push(table);
+    }
+
+    let mut symbols = Vec::new();
+    'groups: for &table in &selectors {
+        for _ in 0..GROUP_SIZE {
+            let symbol = decoders[table].decode(reader).ok_or_else(|| invalid("bad huffman code"))? as u16;
+            symbols.push(symbol);
+            if symbol == mtf::END_OF_BLOCK {
+                break 'groups;
+            }
+        }
+    }
+    let indices = mtf::decode_zero_runs(&symbols, len)?;
+    if indices.len() != len {
+        return Err(invalid("block length mismatch"));
+    }
+    data.extend(bwt::inverse(&mtf::move_to_front_inverse(&indices), primary)?);
+    Ok(())
+}
+
+// Picks the tables and the table used by each group the way bzip2 does:
+// start from tables that each cheaply cover one slice of the alphabet, then
+// alternately assign groups to their cheapest table and rebuild every table
+// from the groups assigned to it.
+fn select_tables(symbols: &[u16]) -> (Vec<Vec<u32>>, Vec<usize>) {
+    let table_count = match symbols.len() {
+        0..=199 => 2,
+        200..=599 => 3,
+        600..=1199 => 4,
+        1200..=2399 => 5,
+        _ => MAX_TABLES,
+    };
+    let mut freqs = vec![0u64; mtf::ALPHABET_SIZE];
+    for &symbol in symbols {
+        freqs[symbol as usize] += 1;
+    }
+
+    let mut tables = vec![vec![15u32; mtf::ALPHABET_SIZE]; table_count];
+    let mut remaining: u64 = symbols.len() as u64;
+    let mut start = 0;
+    for (part, lengths) in tables.iter_mut().enumerate() {
+        let target = remaining / (table_count - part) as u64;
+        let mut end = start;
+        let mut covered = 0;
+        while end < mtf::ALPHABET_SIZE && (covered < target || end == start) {
+            covered += freqs[end];
+            end += 1;
+        }
+        for len in &mut lengths[start..end] {
+            *len = 0;
+        }
+        remaining -= covered;
+        start = end;
+    }
+
+    let mut selectors = Vec::new();
+    for _ in 0..SELECTION_ROUNDS {
+        selectors.clear();
+        let mut table_freqs = vec![vec![0u64; mtf::ALPHABET_SIZE]; table_count];
+        for group in symbols.chunks(GROUP_SIZE) {
+            let cost = |lengths: &Vec<u32>| group.iter().map(|&s| lengths[s as usize]).sum::<u32>();
+            let best = (0..table_count).min_by_key(|&t| cost(&tables[t])).unwrap();
+            for &symbol in group {
+                table_freqs[best][symbol as usize] += 1;
+            }
+            selectors.push(best);
+        }
+        for (lengths, freqs) in tables.iter_mut().zip(&table_freqs) {
+            // Every table keeps a code for every symbol
+            let weights: Vec<u64> = freqs.iter().map(|&f| f.max(1)).collect();
+            *lengths = huffman::code_lengths(&weights, MAX_CODE_LENGTH);
+        }
+    }
+    (tables, selectors)
+}
+
+fn read_bit(reader: &mut BitReader) -> io::Result<bool> {
+    reader.read_bit().ok_or_else(|| invalid("unexpected end of data"))
+}
+
+fn read_bits(reader: &mut BitReader, count: u32) -> io::Result<u64> {
+    reader.read_bits(count).ok_or_else(|| invalid("unexpected end of data"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let text = b"banana bandana, cabana; ";
+        (0..len).map(|i| text[i % text.len()] ^ u8::from(i % 7 == 0)).collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        for data in [Vec::new(), b"a".to_vec(), vec![0; 1000], sample(5000), (0..=255).collect()] {
+            assert_eq!(decompress(&compress(&data), usize::MAX).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn round_trips_several_blocks() {
+        let data = sample(2500);
+        let compressed = compress_with_block_size(&data, 1000).unwrap();
+        assert_eq!(decompress(&compressed, usize::MAX).unwrap(), data);
+        // A final block shorter than the rest
+        assert_eq!(decompress(&compress_with_block_size(&data, 999).unwrap(), usize::MAX).unwrap(), data);
+    }
+
+    #[test]
+    fn respects_the_output_limit() {
+        let data = sample(2500);
+        let compressed = compress_with_block_size(&data, 1000).unwrap();
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+        // The last block is rejected, not cut short
+        let err = decompress(&compressed, data.len() - 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(decompress(&compressed, 1000).is_err());
+        assert!(decompress(&compress(b""), 0).is_ok());
+    }
+
+    #[test]
+    fn rejects_block_sizes_it_cannot_read_back() {
+        for block_size in [0, BLOCK_SIZE + 1] {
+            let err = compress_with_block_size(b"data", block_size).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    // Overwrites one of the first block's u32 header fields, which start
+    // right after the leading "another block" bit
+    fn set_field(compressed: &mut [u8], field: usize, value: u32) {
+        for i in 0..32 {
+            let pos = 1 + 32 * field + i;
+            let mask = 1 << (pos % 8);
+            if (value >> i) & 1 == 1 {
+                compressed[pos / 8] |= mask;
+            } else {
+                compressed[pos / 8] &= !mask;
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_corrupted_length() {
+        let mut compressed = compress(&sample(300));
+        set_field(&mut compressed, 0, u32::MAX);
+        assert_eq!(decompress(&compressed, usize::MAX).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        set_field(&mut compressed, 0, BLOCK_SIZE as u32 + 1);
+        assert!(decompress(&compressed, usize::MAX).is_err());
+        set_field(&mut compressed, 0, 299);
+        assert!(decompress(&compressed, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn rejects_a_corrupted_primary_index() {
+        let mut compressed = compress(&sample(300));
+        set_field(&mut compressed, 1, 300);
+        assert!(decompress(&compressed, usize::MAX).is_err());
+        set_field(&mut compressed, 1, u32::MAX);
+        assert!(decompress(&compressed, usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::io;
+
+use crate::bitio::BitWriter;
+use crate::flat::{FlatDecoder, Slot};
+use crate::huffman::Unseen;
+
+// An immutable Huffman model laid out in flat tables: the decoding tree as
+// an array of nodes and the code of every byte. Unlike `HuffmanModel` it is
+// `Send + Sync`, so one model can serve many threads at once. Built with
+// `HuffmanModel::compile`.
+#[derive(Clone, Debug)]
+pub struct CompiledModel {
+    decoder: FlatDecoder,
+    codes: CodeTable,
+}
+
+impl CompiledModel {
+    pub(crate) fn from_nodes(nodes: Vec<[Slot; 2]>) -> Self {
+        let mut codes = CodeTable::new();
+        let mut stack = vec![(0u16, Vec::new())];
+        while let Some((node, path)) = stack.pop() {
+            for (bit, slot) in nodes[node as usize].iter().enumerate() {
+                let mut code = path.clone();
+                code.push(bit == 1);
+                match *slot {
+                    Slot::Missing => {}
+                    Slot::Node(child) => stack.push((child, code)),
+                    Slot::Byte(c) => codes.insert(c as usize, &code),
+                    Slot::Escape => codes.insert(ESCAPE, &code),
+                }
+            }
+        }
+        Self { decoder: FlatDecoder::from_nodes(nodes), codes }
+    }
+
+    pub fn has_escape(&self) -> bool {
+        self.codes.len(ESCAPE) > 0
+    }
+
+    // Length in bits of the code for `c`, if the model has one
+    pub fn code_len(&self, c: u8) -> Option<u32> {
+        let len = self.codes.len(c as usize);
+        (len > 0).then_some(len)
+    }
+
+    // The decoding half on its own, which also works without std
+    pub fn decoder(&self) -> &FlatDecoder {
+        &self.decoder
+    }
+
+    // Same output as `huffman::encode_with` on the model this was compiled
+    // from.
+    pub fn encode(&self, data: &[u8], unseen: Unseen) -> io::Result<Vec<u8>> {
+        self.codes.encode(data, unseen)
+    }
+
+    // Decodes `len` bytes from `bits`, ignoring the padding after them.
+    pub fn decode(&self, bits: &[u8], len: usize) -> io::Result<Vec<u8>> {
+        Ok(self.decoder.decode(bits, len)?)
+    }
+}
+
+// Index of the escape code in a `CodeTable`, after the 256 bytes
+pub(crate) const ESCAPE: usize = 256;
+
+// The code of every byte and of the escape, as the code itself and its
+// length in bits. Codes longer than 32 bits only come out of degenerate
+// trees; for those the code field is where the code starts in `long`,
+// which holds them 32 bits per word.
+#[derive(Clone, Debug)]
+pub(crate) struct CodeTable {
+    codes: [(u32, u16); 257],
+    long: Vec<u32>,
+}
+
+impl CodeTable {
+    pub(crate) fn new() -> Self {
+        Self { codes: [(0, 0); 257], long: Vec::new() }
+    }
+
+    // Sets the code at `index` to `bits`, the path down from the root
+    pub(crate) fn insert(&mut self, index: usize, bits: &[bool]) {
+        let word = |bits: &[bool]| bits.iter().fold(0u32, |word, &bit| (word << 1) | bit as u32);
+        let code = if bits.len() <= 32 {
+            word(bits)
+        } else {
+            let start = self.long.len() as u32;
+            self.long.extend(bits.chunks(32).map(word));
+            start
+        };
+        self.codes[index] = (code, bits.len() as u16);
+    }
+
+    // Zero for a byte without a code
+    pub(crate) fn len(&self, index: usize) -> u32 {
+        self.codes[index].1 as u32
+    }
+
+    fn push(&self, writer: &mut BitWriter, index: usize) {
+        let (code, len) = self.codes[index];
+        let len = len as u32;
+        if len <= 32 {
+            writer.push_code(code, len);
+            return;
+        }
+        for (i, &word) in self.long[code as usize..][..len.div_ceil(32) as usize].iter().enumerate() {
+            writer.push_code(word, (len - 32 * i as u32).min(32));
+        }
+    }
+
+    pub(crate) fn encode(&self, data: &[u8], unseen: Unseen) -> io::Result<Vec<u8>> {
+        let mut writer = BitWriter::new();
+        for &c in data {
+            match (self.len(c as usize), self.len(ESCAPE), unseen) {
+                (1.., _, _) => self.push(&mut writer, c as usize),
+                (0, 1.., Unseen::Escape) => {
+                    self.push(&mut writer, ESCAPE);
+                    writer.push_bits(c as u64, 8);
+                }
+                (0, _, _) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("byte {} is not in the model", c)));
+                }
+            }
+        }
+        Ok(writer.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::bitio;
+    use crate::flat::{HAS_LEFT, HAS_RIGHT, LEAF};
+    use crate::frequency::Frequencies;
+    use crate::huffman::{self, HuffmanModel};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn compiled_model_is_send_and_sync() {
+        assert_send_sync::<CompiledModel>();
+    }
+
+    #[test]
+    fn matches_tree_encoding_and_decoding() {
+        let data = b"abracadabra, the quick brown fox jumps over the lazy dog".repeat(7);
+        let model = HuffmanModel::from_data(&data);
+        let compiled = model.compile();
+        let bits = compiled.encode(&data, Unseen::Reject).unwrap();
+        assert_eq!(bits, huffman::encode(&model, &data).unwrap());
+        assert_eq!(compiled.decode(&bits, data.len()).unwrap(), data);
+        assert_eq!(huffman::decode(&model, &bits, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn escapes_unseen_bytes() {
+        let model = HuffmanModel::with_escape(&Frequencies::from_data(b"aab"));
+        let compiled = model.compile();
+        assert!(compiled.has_escape());
+        assert!(compiled.code_len(b'z').is_none());
+        let bits = compiled.encode(b"abzaz", Unseen::Escape).unwrap();
+        assert_eq!(bits, huffman::encode(&model, b"abzaz").unwrap());
+        assert_eq!(compiled.decode(&bits, 5).unwrap(), b"abzaz");
+        let err = compiled.encode(b"z", Unseen::Reject).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn survives_a_model_round_trip() {
+        let data: Vec<u8> = (0..=255).chain(0..40).collect();
+        let model = HuffmanModel::from_bytes(&HuffmanModel::from_data(&data).to_bytes()).unwrap();
+        let compiled = model.compile();
+        for c in 0..=255u8 {
+            assert!(compiled.code_len(c).is_some());
+        }
+        let bits = compiled.encode(&data, Unseen::Reject).unwrap();
+        assert_eq!(compiled.decode(&bits, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn codes_longer_than_a_word() {
+        // A chain of 70 internal nodes, each with one leaf, so the deepest
+        // codes are 70 bits long
+        let mut bytes = b"HFMM\x02".to_vec();
+        bitio::write_varint(&mut bytes, 141);
+        for c in 0..70 {
+            bytes.extend([HAS_LEFT | HAS_RIGHT, 1, LEAF, 1, c]);
+        }
+        bytes.extend([LEAF, 1, 70]);
+        let model = HuffmanModel::from_bytes(&bytes).unwrap();
+        let compiled = model.compile();
+        assert_eq!(compiled.code_len(69), Some(70));
+        assert_eq!(compiled.code_len(70), Some(70));
+        let data: Vec<u8> = (0..=70).rev().chain(0..=70).collect();
+        let bits = compiled.encode(&data, Unseen::Reject).unwrap();
+        assert_eq!(bits, huffman::encode(&model, &data).unwrap());
+        assert_eq!(compiled.decode(&bits, data.len()).unwrap(), data);
+        assert_eq!(huffman::decode(&model, &bits, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let compiled = HuffmanModel::from_data(b"abcabcabd").compile();
+        let bits = compiled.encode(b"abcabcabd", Unseen::Reject).unwrap();
+        let err = compiled.decode(&bits[..1], 9).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn threads_share_one_model() {
+        let data = b"shared between worker threads".repeat(50);
+        let compiled = Arc::new(HuffmanModel::from_data(&data).compile());
+        let workers: Vec<_> = (0..4)
+            .map(|i| {
+                let compiled = Arc::clone(&compiled);
+                let chunk = data[i * 100..(i + 1) * 100].to_vec();
+                thread::spawn(move || {
+                    let bits = compiled.encode(&chunk, Unseen::Reject).unwrap();
+                    compiled.decode(&bits, chunk.len()).unwrap() == chunk
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert!(worker.join().unwrap());
+        }
+    }
+}
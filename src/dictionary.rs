@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::bitio::{self, BitReader, BitWriter};
+use crate::frequency::Frequencies;
+use crate::huffman::{self, CanonicalDecoder, Unseen};
+
+// A Huffman model trained ahead of time on sample messages. Only its id is
+// written with each message, so small messages don't pay for a table.
+// Symbol ESCAPE stands in for bytes that never showed up in training and is
+// followed by the byte itself.
+const MAGIC: &[u8; 4] = b"HFMD";
+const MAX_CODE_LENGTH: u32 = 24;
+const ESCAPE: usize = 256;
+const ALPHABET_SIZE: usize = 257;
+
+pub struct Dictionary {
+    id: u32,
+    lengths: Vec<u32>,
+    codes: Vec<u32>,
+    decoder: CanonicalDecoder,
+}
+
+#[derive(Default)]
+pub struct DictionarySet {
+    dictionaries: HashMap<u32, Dictionary>,
+}
+
+impl Dictionary {
+    pub fn train<'a, I: IntoIterator<Item = &'a [u8]>>(id: u32, samples: I) -> Self {
+        let mut freqs = Frequencies::new();
+        for sample in samples {
+            freqs.add(sample);
+        }
+        let mut weights = freqs.counts().to_vec();
+        weights.push(1);
+        Self::from_lengths(id, huffman::code_lengths(&weights, MAX_CODE_LENGTH))
+    }
+
+    fn from_lengths(id: u32, lengths: Vec<u32>) -> Self {
+        let codes = huffman::canonical_codes(&lengths);
+        let decoder = CanonicalDecoder::new(&lengths);
+        Self { id, lengths, codes, decoder }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // Magic, id (u32) and the code lengths of all 256 bytes and ESCAPE
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&self.id.to_le_bytes());
+        let mut writer = BitWriter::new();
+        huffman::write_code_lengths(&mut writer, &self.lengths);
+        out.extend(writer.finish());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < 8 || &bytes[..4] != MAGIC {
+            return Err(invalid("not a dictionary"));
+        }
+        let id = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let mut reader = BitReader::new(&bytes[8..]);
+        let lengths = huffman::read_code_lengths(&mut reader, ALPHABET_SIZE, MAX_CODE_LENGTH)?;
+        Ok(Self::from_lengths(id, lengths))
+    }
+
+    // Message layout: dictionary id and message length as varints, then the
+    // codes.
+    pub fn compress(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        self.compress_with(message, Unseen::Escape)
+    }
+
+    pub fn compress_with(&self, message: &[u8], unseen: Unseen) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        bitio::write_varint(&mut out, self.id as u64);
+        bitio::write_varint(&mut out, message.len() as u64);
+        let mut writer = BitWriter::new();
+        for &c in message {
+            let len = self.lengths[c as usize];
+            if len > 0 {
+                writer.push_code(self.codes[c as usize], len);
+            } else if unseen == Unseen::Escape && self.lengths[ESCAPE] > 0 {
+                writer.push_code(self.codes[ESCAPE], self.lengths[ESCAPE]);
+                writer.push_bits(c as u64, 8);
+            } else {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("byte {} is not in the dictionary", c)));
+            }
+        }
+        out.extend(writer.finish());
+        Ok(out)
+    }
+
+    pub fn decompress(&self, compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut pos = 0;
+        let id = bitio::read_varint(compressed, &mut pos).ok_or_else(|| invalid("truncated message"))?;
+        if id != self.id as u64 {
+            return Err(invalid("message uses a different dictionary"));
+        }
+        self.decode_body(compressed, pos)
+    }
+
+    fn decode_body(&self, compressed: &[u8], mut pos: usize) -> io::Result<Vec<u8>> {
+        let len = bitio::read_varint(compressed, &mut pos).ok_or_else(|| invalid("truncated message"))?;
+        let mut reader = BitReader::new(&compressed[pos..]);
+        // Every code is at least one bit long
+        if len > reader.bits_left() as u64 {
+            return Err(invalid("truncated message"));
+        }
+        let mut message = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let mut c = self.decoder.decode(&mut reader).ok_or_else(|| invalid("bad code in message"))?;
+            if c == ESCAPE {
+                c = reader.read_bits(8).ok_or_else(|| invalid("truncated message"))? as usize;
+            }
+            message.push(c as u8);
+        }
+        Ok(message)
+    }
+}
+
+impl DictionarySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // Replaces any dictionary already registered under the same id
+    pub fn insert(&mut self, dictionary: Dictionary) {
+        self.dictionaries.insert(dictionary.id, dictionary);
+    }
+    pub fn get(&self, id: u32) -> Option<&Dictionary> {
+        self.dictionaries.get(&id)
+    }
+    pub fn compress(&self, id: u32, message: &[u8]) -> io::Result<Vec<u8>> {
+        let dictionary = self.get(id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown dictionary"))?;
+        dictionary.compress(message)
+    }
+    // Decodes a message with whichever dictionary its id names
+    pub fn decompress(&self, compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut pos = 0;
+        let id = bitio::read_varint(compressed, &mut pos).ok_or_else(|| invalid("truncated message"))?;
+        let dictionary = u32::try_from(id).ok()
+            .and_then(|id| self.get(id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown dictionary"))?;
+        dictionary.decode_body(compressed, pos)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: [&[u8]; 3] = [
+        b"{\"user\":\"ada\",\"status\":\"online\"}",
+        b"{\"user\":\"grace\",\"status\":\"away\"}",
+        b"{\"user\":\"alan\",\"status\":\"offline\"}",
+    ];
+
+    fn trained(id: u32) -> Dictionary {
+        Dictionary::train(id, SAMPLES)
+    }
+
+    #[test]
+    fn round_trips_through_serialization() {
+        let dictionary = Dictionary::from_bytes(&trained(7).to_bytes()).unwrap();
+        assert_eq!(dictionary.id(), 7);
+        assert_eq!(dictionary.to_bytes(), trained(7).to_bytes());
+        let message = b"{\"user\":\"linus\",\"status\":\"online\"}";
+        let compressed = dictionary.compress(message).unwrap();
+        assert!(compressed.len() < message.len());
+        assert_eq!(trained(7).decompress(&compressed).unwrap(), message);
+    }
+
+    #[test]
+    fn escapes_bytes_missing_from_training() {
+        let dictionary = trained(1);
+        let message = b"{\"user\":\"ZOE\",\"status\":\"\xff\"}";
+        let compressed = dictionary.compress(message).unwrap();
+        assert_eq!(dictionary.decompress(&compressed).unwrap(), message);
+        let err = dictionary.compress_with(message, Unseen::Reject).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn rejects_another_dictionarys_message() {
+        let compressed = trained(1).compress(b"{\"user\":\"ada\"}").unwrap();
+        let err = trained(2).decompress(&compressed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let dictionary = trained(300);
+        let compressed = dictionary.compress(b"{\"user\":\"grace\",\"status\":\"online\"}").unwrap();
+        for len in [0, 1, 2, compressed.len() / 2, compressed.len() - 1] {
+            assert!(dictionary.decompress(&compressed[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_dictionaries() {
+        let bytes = trained(1).to_bytes();
+        assert!(Dictionary::from_bytes(&bytes[..7]).is_err());
+        assert!(Dictionary::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(Dictionary::from_bytes(&bad_magic).is_err());
+    }
+
+    #[test]
+    fn set_picks_the_dictionary_by_id() {
+        let mut set = DictionarySet::new();
+        set.insert(trained(1));
+        set.insert(Dictionary::train(2, [&b"completely different samples"[..]]));
+        for id in [1, 2] {
+            let compressed = set.compress(id, b"{\"user\":\"ada\"}").unwrap();
+            assert_eq!(set.decompress(&compressed).unwrap(), b"{\"user\":\"ada\"}");
+        }
+        assert_eq!(set.compress(3, b"x").unwrap_err().kind(), io::ErrorKind::NotFound);
+        let orphan = trained(3).compress(b"x").unwrap();
+        assert_eq!(set.decompress(&orphan).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
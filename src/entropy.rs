@@ -0,0 +1,293 @@
+use std::io;
+
+use crate::{ans, range};
+use crate::frequency::Frequencies;
+use crate::transform::{Huffman, Rle, Transform};
+
+// Block container for the entropy coders. Every block starts with the
+// backend that coded it, its decoded length (u32) and its payload length
+// (u32), so the backend can be chosen block by block.
+pub const BLOCK_SIZE: usize = 1 << 16;
+pub const MAX_BLOCK_SIZE: usize = 1 << 24;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    Huffman,
+    Range,
+    Ans,
+    // The block as is, for data no coder can shrink
+    Stored,
+    // Run-length coding alone
+    Rle,
+}
+
+impl Backend {
+    fn tag(self) -> u8 {
+        match self {
+            Backend::Huffman => 0,
+            Backend::Range => 1,
+            Backend::Ans => 2,
+            Backend::Stored => 3,
+            Backend::Rle => 4,
+        }
+    }
+    fn from_tag(tag: u8) -> io::Result<Self> {
+        match tag {
+            0 => Ok(Backend::Huffman),
+            1 => Ok(Backend::Range),
+            2 => Ok(Backend::Ans),
+            3 => Ok(Backend::Stored),
+            4 => Ok(Backend::Rle),
+            _ => Err(invalid("unknown entropy backend")),
+        }
+    }
+    pub fn encode(self, block: &[u8]) -> Vec<u8> {
+        match self {
+            Backend::Huffman => Huffman.forward(block),
+            Backend::Range => range::encode(block),
+            Backend::Ans => ans::encode(block),
+            Backend::Stored => block.to_vec(),
+            Backend::Rle => Rle.forward(block),
+        }
+    }
+    // Payload size `encode` would produce for `block`, without coding it.
+    // Exact for Huffman, stored and RLE; for range and ANS it is the order-0
+    // entropy plus their length and frequency table.
+    pub fn estimate(self, block: &[u8]) -> usize {
+        match self {
+            Backend::Huffman => Huffman::encoded_len(block),
+            Backend::Range | Backend::Ans => {
+                let freqs = Frequencies::from_data(block);
+                let bits = freqs.entropy() * block.len() as f64;
+                8 + 32 + 2 * freqs.symbols().count() + (bits / 8.0).ceil() as usize
+            }
+            Backend::Stored => block.len(),
+            Backend::Rle => Rle::encoded_len(block),
+        }
+    }
+    pub fn decode(self, payload: &[u8], len: usize) -> io::Result<Vec<u8>> {
+        let block = match self {
+            Backend::Huffman => Huffman.inverse(payload, len)?,
+            Backend::Range => range::decode(payload, len)?,
+            Backend::Ans => ans::decode(payload, len)?,
+            Backend::Stored => payload.to_vec(),
+            Backend::Rle => Rle.inverse(payload, len)?,
+        };
+        if block.len() != len {
+            return Err(invalid("entropy block length mismatch"));
+        }
+        Ok(block)
+    }
+}
+
+// Codes every block with `backend`, except where storing it raw or RLE
+// alone is estimated to come out smaller.
+pub fn compress(data: &[u8], backend: Backend) -> Vec<u8> {
+    compress_with(data, BLOCK_SIZE, |block| cheapest(backend, block)).expect("default block size is valid")
+}
+
+// `backend`, or stored or RLE if either is estimated smaller for `block`.
+// Ties go to `backend`, then to storing the block.
+pub fn cheapest(backend: Backend, block: &[u8]) -> Backend {
+    [backend, Backend::Stored, Backend::Rle].into_iter().min_by_key(|b| b.estimate(block)).unwrap()
+}
+
+// Codes each block of `block_size` bytes with the backend `choose` picks for it.
+pub fn compress_with<F: FnMut(&[u8]) -> Backend>(data: &[u8], block_size: usize, mut choose: F) -> io::Result<Vec<u8>> {
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "entropy block size must be between 1 and MAX_BLOCK_SIZE"));
+    }
+    let mut out = Vec::new();
+    for block in data.chunks(block_size) {
+        let backend = choose(block);
+        let payload = backend.encode(block);
+        out.push(backend.tag());
+        out.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend(payload);
+    }
+    Ok(out)
+}
+
+// Fails before decoding a block that would take the output past `max_len`
+// bytes; a single symbol block codes any length in a few bytes, so
+// untrusted input should come with a limit.
+pub fn decompress(compressed: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut rest = compressed;
+    while !rest.is_empty() {
+        if rest.len() < 9 {
+            return Err(invalid("truncated block header"));
+        }
+        let backend = Backend::from_tag(rest[0])?;
+        let len = u32::from_le_bytes(rest[1..5].try_into().unwrap()) as usize;
+        let payload_len = u32::from_le_bytes(rest[5..9].try_into().unwrap()) as usize;
+        if len > MAX_BLOCK_SIZE {
+            return Err(invalid("block too long"));
+        }
+        if len > max_len - data.len() {
+            return Err(invalid("entropy coded data too large"));
+        }
+        let payload = rest.get(9..9 + payload_len).ok_or_else(|| invalid("truncated block"))?;
+        data.extend(backend.decode(payload, len)?);
+        rest = &rest[9 + payload_len..];
+    }
+    Ok(data)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Backend tag of every block in `compressed`
+    fn tags(mut compressed: &[u8]) -> Vec<u8> {
+        let mut tags = Vec::new();
+        while !compressed.is_empty() {
+            tags.push(compressed[0]);
+            let payload_len = u32::from_le_bytes(compressed[5..9].try_into().unwrap()) as usize;
+            compressed = &compressed[9 + payload_len..];
+        }
+        tags
+    }
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    // The coders with a frequency table header
+    const MODELLED: [Backend; 2] = [Backend::Range, Backend::Ans];
+
+    fn round_trip(backend: Backend, data: &[u8]) -> Vec<u8> {
+        let payload = backend.encode(data);
+        assert_eq!(backend.decode(&payload, data.len()).unwrap(), data, "{:?}", backend);
+        payload
+    }
+
+    #[test]
+    fn modelled_backends_round_trip() {
+        for backend in MODELLED {
+            round_trip(backend, b"");
+            round_trip(backend, &(0..=255).collect::<Vec<u8>>());
+            round_trip(backend, &(0..=255).rev().cycle().take(5000).collect::<Vec<u8>>());
+            round_trip(backend, b"range coders carry into bytes they already wrote");
+            round_trip(backend, &noise(5000));
+        }
+    }
+
+    #[test]
+    fn modelled_backends_round_trip_skewed_data() {
+        // One byte in a thousand is not 'a', pushing the rare bytes down to
+        // the smallest probability the model has
+        let data: Vec<u8> = (0..20_000u32).map(|i| if i % 1000 == 999 { (i / 1000) as u8 } else { b'a' }).collect();
+        for backend in MODELLED {
+            assert!(round_trip(backend, &data).len() < data.len() / 20, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn single_symbol_costs_nothing_per_byte() {
+        for backend in MODELLED {
+            let short = round_trip(backend, b"z");
+            let long = round_trip(backend, &[b'z'; 100_000]);
+            assert_eq!(short.len(), long.len(), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn modelled_backends_reject_blocks_longer_than_the_limit() {
+        for backend in MODELLED {
+            let payload = backend.encode(&[b'z'; 1000]);
+            assert_eq!(backend.decode(&payload, 999).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert!(backend.decode(&payload, 1000).is_ok());
+        }
+    }
+
+    #[test]
+    fn modelled_backends_reject_truncated_payloads() {
+        let data = b"a longer message so the coded data spans several bytes";
+        for backend in MODELLED {
+            let payload = backend.encode(data);
+            for len in [0, 7, 8, 39, 42, payload.len() - 5] {
+                assert!(backend.decode(&payload[..len], data.len()).is_err(), "{:?} {}", backend, len);
+            }
+        }
+    }
+
+    #[test]
+    fn estimates_are_exact_where_promised() {
+        let text = b"the estimate has to match the coder byte for byte".repeat(40);
+        for data in [text, noise(5000), vec![7; 3000], Vec::new()] {
+            for backend in [Backend::Huffman, Backend::Stored, Backend::Rle] {
+                assert_eq!(backend.estimate(&data), backend.encode(&data).len(), "{:?}", backend);
+            }
+        }
+    }
+
+    #[test]
+    fn incompressible_blocks_are_stored() {
+        let data = noise(3 * BLOCK_SIZE / 2);
+        for backend in [Backend::Huffman, Backend::Range, Backend::Ans] {
+            let compressed = compress(&data, backend);
+            assert_eq!(tags(&compressed), vec![Backend::Stored.tag(); 2]);
+            assert_eq!(compressed.len(), data.len() + 2 * 9);
+            assert_eq!(decompress(&compressed, usize::MAX).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn runs_fall_back_to_rle() {
+        // Long runs of many different bytes: cheap for RLE, while Huffman
+        // still spends a code on every byte of every run
+        let data: Vec<u8> = (0..=255u8).flat_map(|c| [c; 200]).collect();
+        let compressed = compress(&data, Backend::Huffman);
+        assert!(tags(&compressed).iter().all(|&tag| tag == Backend::Rle.tag()));
+        assert_eq!(decompress(&compressed, usize::MAX).unwrap(), data);
+    }
+
+    #[test]
+    fn compressible_blocks_keep_their_backend() {
+        let data = b"plain text compresses well enough for huffman to win".repeat(100);
+        let compressed = compress(&data, Backend::Huffman);
+        assert_eq!(tags(&compressed), vec![Backend::Huffman.tag()]);
+        assert_eq!(decompress(&compressed, usize::MAX).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_block_sizes_it_cannot_read_back() {
+        for block_size in [0, MAX_BLOCK_SIZE + 1] {
+            let err = compress_with(b"data", block_size, |_| Backend::Huffman).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn respects_the_output_limit() {
+        let data = vec![b'z'; 3 * BLOCK_SIZE];
+        for backend in MODELLED {
+            let compressed = compress_with(&data, BLOCK_SIZE, |_| backend).unwrap();
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+            let err = decompress(&compressed, data.len() - 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(decompress(&compressed, 0).is_err());
+        }
+    }
+
+    #[test]
+    fn stored_blocks_must_match_their_length() {
+        let mut compressed = compress_with(b"raw", BLOCK_SIZE, |_| Backend::Stored).unwrap();
+        compressed[1] = 4;
+        assert!(decompress(&compressed, usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::bitio::{self, BitReader};
+
+// Decoding that needs neither std nor the `Rc` trees of `huffman`: a model
+// becomes a flat table of nodes, read straight from the bytes
+// `HuffmanModel::to_bytes` and `HuffmanState::save` write. The only
+// allocation is the table itself; `decode_into` fills a buffer the caller
+// owns.
+
+// Layout shared with `huffman`
+pub(crate) const FILE_MAGIC: &[u8; 4] = b"HFMN";
+pub(crate) const FILE_VERSION: u8 = 3;
+pub(crate) const MODEL_MAGIC: &[u8; 4] = b"HFMM";
+pub(crate) const MODEL_VERSION: u8 = 2;
+
+// How a file's data is stored: Huffman coded after the tree, as is, or
+// run-length coded the way `transform::Rle` does it
+pub(crate) const MODE_HUFFMAN: u8 = 0;
+pub(crate) const MODE_STORED: u8 = 1;
+pub(crate) const MODE_RLE: u8 = 2;
+
+// Tree node flags
+pub(crate) const LEAF: u8 = 1;
+pub(crate) const HAS_LEFT: u8 = 2;
+pub(crate) const HAS_RIGHT: u8 = 4;
+// 256 bytes and an escape make at most 257 leaves
+pub(crate) const MAX_TREE_NODES: u64 = 2 * 257 - 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    // Not a model or file in a layout this decoder knows
+    Malformed(&'static str),
+    // A file whose transforms only the std build can undo
+    Unsupported,
+    BadCode,
+    Truncated,
+    OutputTooSmall,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Malformed(message) => f.write_str(message),
+            DecodeError::Unsupported => f.write_str("huffman file uses transforms"),
+            DecodeError::BadCode => f.write_str("bad huffman code"),
+            DecodeError::Truncated => f.write_str("huffman data ended early"),
+            DecodeError::OutputTooSmall => f.write_str("output buffer too small"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+#[cfg(feature = "std")]
+impl From<DecodeError> for std::io::Error {
+    fn from(err: DecodeError) -> Self {
+        let kind = match err {
+            DecodeError::Truncated => std::io::ErrorKind::UnexpectedEof,
+            DecodeError::OutputTooSmall => std::io::ErrorKind::InvalidInput,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+// What one side of a flattened tree node leads to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Slot {
+    Missing,
+    Node(u16),
+    Byte(u8),
+    Escape,
+}
+
+#[derive(Clone, Debug)]
+pub struct FlatDecoder {
+    // nodes[0] is the root; each entry holds the left and right slot
+    nodes: Vec<[Slot; 2]>,
+}
+
+impl FlatDecoder {
+    pub(crate) fn from_nodes(nodes: Vec<[Slot; 2]>) -> Self {
+        Self { nodes }
+    }
+
+    // Reads a model written by `HuffmanModel::to_bytes`.
+    pub fn from_model_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < 5 || &bytes[..4] != MODEL_MAGIC {
+            return Err(DecodeError::Malformed("not a huffman model"));
+        }
+        if bytes[4] != MODEL_VERSION {
+            return Err(DecodeError::Malformed("unsupported huffman model version"));
+        }
+        let mut pos = 5;
+        read_tree(bytes, &mut pos)
+    }
+
+    // Decodes exactly `out.len()` bytes from `bits`, ignoring the padding
+    // after them.
+    pub fn decode_into(&self, bits: &[u8], out: &mut [u8]) -> Result<(), DecodeError> {
+        let mut reader = BitReader::new(bits);
+        let mut node = 0;
+        let mut filled = 0;
+        while filled < out.len() {
+            let bit = reader.read_bit().ok_or(DecodeError::Truncated)?;
+            out[filled] = match self.nodes[node][bit as usize] {
+                Slot::Missing => return Err(DecodeError::BadCode),
+                Slot::Node(child) => {
+                    node = child as usize;
+                    continue;
+                }
+                Slot::Byte(c) => c,
+                Slot::Escape => reader.read_bits(8).ok_or(DecodeError::Truncated)? as u8,
+            };
+            filled += 1;
+            node = 0;
+        }
+        Ok(())
+    }
+
+    pub fn decode(&self, bits: &[u8], len: usize) -> Result<Vec<u8>, DecodeError> {
+        // Every byte takes at least one bit, which bounds what a bogus `len`
+        // can make us allocate
+        if len > bits.len().saturating_mul(8) {
+            return Err(DecodeError::Truncated);
+        }
+        let mut out = vec![0; len];
+        self.decode_into(bits, &mut out)?;
+        Ok(out)
+    }
+}
+
+// A file written by `HuffmanState::save` without transforms, which is what
+// `HuffmanState::new` produces.
+#[derive(Clone, Debug)]
+pub struct FlatFile<'a> {
+    // None unless the data is Huffman coded
+    decoder: Option<FlatDecoder>,
+    mode: u8,
+    len: usize,
+    payload: &'a [u8],
+}
+
+impl<'a> FlatFile<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < 6 || &bytes[..4] != FILE_MAGIC {
+            return Err(DecodeError::Malformed("not a huffman file"));
+        }
+        if bytes[4] != FILE_VERSION {
+            return Err(DecodeError::Malformed("unsupported huffman file version"));
+        }
+        // Pipeline header: a stage count and the stages
+        if bytes[5] != 0 {
+            return Err(DecodeError::Unsupported);
+        }
+        let mode = *bytes.get(6).ok_or(DecodeError::Truncated)?;
+        let len = bytes.get(7..15).ok_or(DecodeError::Truncated)?;
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        let len = usize::try_from(len).map_err(|_| DecodeError::Malformed("huffman file too large"))?;
+        let mut pos = 15;
+        let decoder = match mode {
+            MODE_HUFFMAN => Some(read_tree(bytes, &mut pos)?),
+            MODE_STORED | MODE_RLE => None,
+            _ => return Err(DecodeError::Malformed("unknown huffman file mode")),
+        };
+        Ok(Self { decoder, mode, len, payload: &bytes[pos..] })
+    }
+
+    // Size of the decoded data
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The file's model, unless its data was stored or run-length coded
+    pub fn decoder(&self) -> Option<&FlatDecoder> {
+        self.decoder.as_ref()
+    }
+
+    // Decodes into the start of `out`, which must hold at least `len()`
+    // bytes.
+    pub fn decode_into(&self, out: &mut [u8]) -> Result<(), DecodeError> {
+        let out = out.get_mut(..self.len).ok_or(DecodeError::OutputTooSmall)?;
+        match (&self.decoder, self.mode) {
+            (Some(decoder), _) => decoder.decode_into(self.payload, out),
+            (None, MODE_STORED) => {
+                if self.payload.len() != out.len() {
+                    return Err(DecodeError::Malformed("stored data does not match its length"));
+                }
+                out.copy_from_slice(self.payload);
+                Ok(())
+            }
+            (None, _) => expand_runs(self.payload, out),
+        }
+    }
+
+    pub fn decode(&self) -> Result<Vec<u8>, DecodeError> {
+        match &self.decoder {
+            Some(decoder) => decoder.decode(self.payload, self.len),
+            None => {
+                // Five bytes of RLE expand to at most 259
+                if self.len > self.payload.len().saturating_mul(52) {
+                    return Err(DecodeError::Truncated);
+                }
+                let mut out = vec![0; self.len];
+                self.decode_into(&mut out)?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+// Undoes `transform::Rle`: after four equal bytes a count of further
+// repeats follows. The runs must fill `out` exactly.
+fn expand_runs(payload: &[u8], out: &mut [u8]) -> Result<(), DecodeError> {
+    let mut filled = 0;
+    let mut i = 0;
+    while i < payload.len() {
+        let c = payload[i];
+        let mut run = payload[i..].iter().take(4).take_while(|&&d| d == c).count();
+        i += run;
+        if run == 4 {
+            run += *payload.get(i).ok_or(DecodeError::Truncated)? as usize;
+            i += 1;
+        }
+        let dest = out.get_mut(filled..filled + run).ok_or(DecodeError::Malformed("run-length data too long"))?;
+        dest.fill(c);
+        filled += run;
+    }
+    if filled != out.len() {
+        return Err(DecodeError::Truncated);
+    }
+    Ok(())
+}
+
+// The node count, then the nodes in preorder, laid out as
+// `HuffmanModel::write_tree` writes them and checked the way `read_tree`
+// checks them.
+fn read_tree(bytes: &[u8], pos: &mut usize) -> Result<FlatDecoder, DecodeError> {
+    let count = bitio::read_varint(bytes, pos).ok_or(DecodeError::Truncated)?;
+    if count == 0 || count > MAX_TREE_NODES {
+        return Err(DecodeError::Malformed("bad huffman tree size"));
+    }
+    let (flags, _) = read_node(bytes, pos)?;
+    // A leaf at the root would leave its byte with an empty code
+    if flags & LEAF != 0 {
+        return Err(DecodeError::Malformed("huffman tree root is a leaf"));
+    }
+    let mut nodes = vec![[Slot::Missing; 2]];
+    // Nodes still waiting on children, and whether each side is owed
+    let mut pending = vec![(0, flags & HAS_LEFT != 0, flags & HAS_RIGHT != 0)];
+    for _ in 1..count {
+        let (flags, c) = read_node(bytes, pos)?;
+        let slot = match c {
+            Some(c) => Slot::Byte(c),
+            None if flags & (HAS_LEFT | HAS_RIGHT) == 0 => Slot::Escape,
+            None => {
+                nodes.push([Slot::Missing; 2]);
+                Slot::Node((nodes.len() - 1) as u16)
+            }
+        };
+        let parent = pending.last_mut().ok_or(DecodeError::Malformed("huffman tree has extra nodes"))?;
+        if parent.1 {
+            parent.1 = false;
+            nodes[parent.0][0] = slot;
+        } else {
+            parent.2 = false;
+            nodes[parent.0][1] = slot;
+        }
+        if !parent.1 && !parent.2 {
+            pending.pop();
+        }
+        if let Slot::Node(index) = slot {
+            pending.push((index as usize, flags & HAS_LEFT != 0, flags & HAS_RIGHT != 0));
+        }
+    }
+    if pending.iter().any(|(_, left, right)| *left || *right) {
+        return Err(DecodeError::Malformed("huffman tree is missing nodes"));
+    }
+    Ok(FlatDecoder::from_nodes(nodes))
+}
+
+// A node's flags and, for leaves, its byte. Frequencies only matter when
+// building a model, so they are skipped.
+fn read_node(bytes: &[u8], pos: &mut usize) -> Result<(u8, Option<u8>), DecodeError> {
+    let flags = *bytes.get(*pos).ok_or(DecodeError::Truncated)?;
+    *pos += 1;
+    if flags & !(LEAF | HAS_LEFT | HAS_RIGHT) != 0 || (flags & LEAF != 0 && flags != LEAF) {
+        return Err(DecodeError::Malformed("bad huffman tree node"));
+    }
+    bitio::read_varint(bytes, pos).ok_or(DecodeError::Truncated)?;
+    if flags & LEAF == 0 {
+        return Ok((flags, None));
+    }
+    let c = *bytes.get(*pos).ok_or(DecodeError::Truncated)?;
+    *pos += 1;
+    Ok((flags, Some(c)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Root with 'a' on the left and 'b' on the right
+    const TWO_LEAVES: &[u8] = &[b'H', b'F', b'M', b'M', 2, 3, HAS_LEFT | HAS_RIGHT, 3, LEAF, 2, b'a', LEAF, 1, b'b'];
+
+    #[test]
+    fn decodes_a_hand_built_model() {
+        let decoder = FlatDecoder::from_model_bytes(TWO_LEAVES).unwrap();
+        let mut out = [0u8; 3];
+        decoder.decode_into(&[0b010], &mut out).unwrap();
+        assert_eq!(&out, b"aba");
+        let mut out = [0u8; 9];
+        assert_eq!(decoder.decode_into(&[0b010], &mut out), Err(DecodeError::Truncated));
+        assert_eq!(decoder.decode(&[0b010], 9), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn rejects_malformed_models() {
+        let mut root_leaf = TWO_LEAVES.to_vec();
+        root_leaf[6] = LEAF;
+        assert!(matches!(FlatDecoder::from_model_bytes(&root_leaf), Err(DecodeError::Malformed(_))));
+        let mut extra = TWO_LEAVES.to_vec();
+        extra[5] = 4;
+        extra.extend_from_slice(&[LEAF, 1, b'c']);
+        assert!(matches!(FlatDecoder::from_model_bytes(&extra), Err(DecodeError::Malformed(_))));
+        for end in 0..TWO_LEAVES.len() {
+            assert!(FlatDecoder::from_model_bytes(&TWO_LEAVES[..end]).is_err());
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decodes_files_from_huffman_state() {
+        use crate::huffman::HuffmanState;
+        let data = b"assets decoded on a device without std".repeat(9);
+        let mut bytes = Vec::new();
+        HuffmanState::new(data.clone()).save(&mut bytes).unwrap();
+        let file = FlatFile::parse(&bytes).unwrap();
+        assert!(file.decoder().is_some());
+        assert_eq!(file.len(), data.len());
+        assert_eq!(file.decode().unwrap(), data);
+        let mut out = vec![0u8; data.len() + 5];
+        file.decode_into(&mut out).unwrap();
+        assert_eq!(&out[..data.len()], data.as_slice());
+        assert_eq!(file.decode_into(&mut out[..10]), Err(DecodeError::OutputTooSmall));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decodes_stored_and_run_length_files() {
+        use crate::huffman::HuffmanState;
+        let runs: Vec<u8> = (0..=255u8).flat_map(|c| [c; 50]).collect();
+        for (data, mode) in [((0..=255).collect::<Vec<u8>>(), MODE_STORED), (runs, MODE_RLE)] {
+            let mut bytes = Vec::new();
+            HuffmanState::new(data.clone()).save(&mut bytes).unwrap();
+            assert_eq!(bytes[6], mode);
+            let file = FlatFile::parse(&bytes).unwrap();
+            assert!(file.decoder().is_none());
+            assert_eq!(file.decode().unwrap(), data);
+            assert_eq!(file.decode_into(&mut vec![0; data.len() - 1]), Err(DecodeError::OutputTooSmall));
+            assert!(FlatFile::parse(&bytes[..bytes.len() - 1]).unwrap().decode().is_err());
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn refuses_files_with_transforms() {
+        use crate::huffman::HuffmanState;
+        use crate::transform::{Pipeline, Rle};
+        let mut bytes = Vec::new();
+        HuffmanState::with_pipeline(b"aaaab".to_vec(), Pipeline::new().then(Rle)).save(&mut bytes).unwrap();
+        assert_eq!(FlatFile::parse(&bytes).unwrap_err(), DecodeError::Unsupported);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn agrees_with_the_tree_model() {
+        use crate::frequency::Frequencies;
+        use crate::huffman::{self, HuffmanModel};
+        let model = HuffmanModel::with_escape(&Frequencies::from_data(b"mostly these bytes"));
+        let bytes = model.to_bytes();
+        let decoder = FlatDecoder::from_model_bytes(&bytes).unwrap();
+        let data = b"mostly these bytes, plus others";
+        let bits = huffman::encode(&model, data).unwrap();
+        assert_eq!(decoder.decode(&bits, data.len()).unwrap(), data);
+        // Every cut of the model is rejected by both readers or neither
+        for end in 0..bytes.len() {
+            let flat = FlatDecoder::from_model_bytes(&bytes[..end]).is_ok();
+            assert_eq!(flat, HuffmanModel::from_bytes(&bytes[..end]).is_ok(), "cut at {}", end);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use std::io;
+
+// Byte histogram shared by the entropy coders.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Frequencies {
+    counts: [u64; 256],
+}
+
+impl Default for Frequencies {
+    fn default() -> Self {
+        Self { counts: [0; 256] }
+    }
+}
+
+impl Frequencies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn from_data(data: &[u8]) -> Self {
+        let mut freqs = Self::new();
+        freqs.add(data);
+        freqs
+    }
+    pub fn add(&mut self, data: &[u8]) {
+        for &c in data {
+            self.counts[c as usize] += 1;
+        }
+    }
+    pub fn count(&self, c: u8) -> u64 {
+        self.counts[c as usize]
+    }
+    pub fn counts(&self) -> &[u64; 256] {
+        &self.counts
+    }
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+    // Shannon entropy in bits per byte, zero for an empty histogram
+    pub fn entropy(&self) -> f64 {
+        let total = self.total() as f64;
+        self.symbols()
+            .map(|c| {
+                let p = self.count(c) as f64 / total;
+                -p * p.log2()
+            })
+            .sum()
+    }
+    // Bytes that occur at least once, in increasing order
+    pub fn symbols(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255u8).filter(|&c| self.counts[c as usize] > 0)
+    }
+
+    // Scales the counts so they sum to exactly `target`, keeping every byte
+    // that occurs at a count of at least one. `target` must be at least the
+    // number of distinct bytes.
+    pub fn normalized(&self, target: u32) -> [u32; 256] {
+        let mut scaled = [0u32; 256];
+        let total = self.total();
+        if total == 0 {
+            return scaled;
+        }
+        for c in self.symbols() {
+            let share = self.counts[c as usize] as u128 * target as u128 / total as u128;
+            scaled[c as usize] = (share as u32).max(1);
+        }
+        let sum: u32 = scaled.iter().sum();
+        let largest = (0..256).max_by_key(|&c| scaled[c]).unwrap();
+        if sum <= target {
+            scaled[largest] += target - sum;
+            return scaled;
+        }
+        // Rounding small counts up to one overshot; take it back from the
+        // most frequent bytes
+        let mut excess = sum - target;
+        while excess > 0 {
+            let largest = (0..256).max_by_key(|&c| scaled[c]).unwrap();
+            let take = excess.min(scaled[largest] / 2).max(1);
+            scaled[largest] -= take;
+            excess -= take;
+        }
+        scaled
+    }
+}
+
+// With the `serde` feature the histogram is stored as its 256 counts, by
+// byte value.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Frequencies {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.counts.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Frequencies {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let counts = Vec::<u64>::deserialize(deserializer)?;
+        let counts = counts.try_into().map_err(|counts: Vec<u64>| {
+            serde::de::Error::invalid_length(counts.len(), &"256 byte counts")
+        })?;
+        Ok(Self { counts })
+    }
+}
+
+// Scaled frequencies as a 256 bit map of the bytes present followed by the
+// count of each present byte (u16, little endian).
+pub fn write_scaled(scaled: &[u32; 256], out: &mut Vec<u8>) {
+    let mut present = [0u8; 32];
+    for c in (0..256).filter(|&c| scaled[c] > 0) {
+        present[c / 8] |= 1 << (c % 8);
+    }
+    out.extend_from_slice(&present);
+    for c in (0..256).filter(|&c| scaled[c] > 0) {
+        out.extend_from_slice(&(scaled[c] as u16).to_le_bytes());
+    }
+}
+
+// Reads what `write_scaled` wrote starting at `pos`, returning the counts and
+// the position just past them.
+pub fn read_scaled(payload: &[u8], mut pos: usize) -> io::Result<([u32; 256], usize)> {
+    let truncated = || invalid("truncated frequency table");
+    let present: [u8; 32] = payload.get(pos..pos + 32).ok_or_else(truncated)?.try_into().unwrap();
+    pos += 32;
+    let mut scaled = [0u32; 256];
+    for (c, count) in scaled.iter_mut().enumerate() {
+        if present[c / 8] & (1 << (c % 8)) != 0 {
+            let bytes = payload.get(pos..pos + 2).ok_or_else(truncated)?;
+            *count = u16::from_le_bytes([bytes[0], bytes[1]]) as u32;
+            pos += 2;
+        }
+    }
+    Ok((scaled, pos))
+}
+
+// Header of the range and ANS payloads: the symbol count (u64) followed by
+// the scaled frequencies.
+pub fn write_header(len: usize, scaled: &[u32; 256]) -> Vec<u8> {
+    let mut out = (len as u64).to_le_bytes().to_vec();
+    write_scaled(scaled, &mut out);
+    out
+}
+
+// Reads what `write_header` wrote, returning the symbol count, the scaled
+// frequencies and the position of the coded data, or None for an empty
+// block. The frequencies must sum to `total`; a byte with all of it costs
+// no bits, so the count is checked against `max_len` rather than the
+// payload size.
+pub fn read_header(payload: &[u8], total: u32, max_len: usize) -> io::Result<Option<(usize, [u32; 256], usize)>> {
+    let len_bytes = payload.get(..8).ok_or_else(|| invalid("truncated entropy block"))?;
+    let len = u64::from_le_bytes(len_bytes.try_into().unwrap());
+    let (scaled, pos) = read_scaled(payload, 8)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if scaled.iter().sum::<u32>() != total {
+        return Err(invalid("scaled frequencies do not sum to the total"));
+    }
+    if len > max_len as u64 {
+        return Err(invalid("entropy block too long"));
+    }
+    Ok(Some((len as usize, scaled, pos)))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_normalized(freqs: &Frequencies, target: u32) -> [u32; 256] {
+        let scaled = freqs.normalized(target);
+        assert_eq!(scaled.iter().sum::<u32>(), target);
+        for c in 0..=255u8 {
+            assert_eq!(scaled[c as usize] > 0, freqs.count(c) > 0, "byte {}", c);
+        }
+        scaled
+    }
+
+    #[test]
+    fn normalizes_proportionally() {
+        let freqs = Frequencies::from_data(&b"aaaabbcd".repeat(10));
+        let scaled = assert_normalized(&freqs, 1024);
+        assert_eq!([scaled[b'a' as usize], scaled[b'b' as usize], scaled[b'c' as usize]], [512, 256, 128]);
+    }
+
+    #[test]
+    fn keeps_rare_bytes() {
+        // 255 bytes seen once next to one seen a million times: each rare
+        // byte still gets a count, taken from the common one
+        let mut freqs = Frequencies::from_data(&(0..=254).collect::<Vec<u8>>());
+        freqs.add(&vec![255; 1_000_000]);
+        let scaled = assert_normalized(&freqs, 2048);
+        assert_eq!(scaled[255], 2048 - 255);
+        // Exactly one count per byte present
+        let all = Frequencies::from_data(&(0..=255).collect::<Vec<u8>>());
+        assert_eq!(assert_normalized(&all, 256), [1; 256]);
+    }
+
+    #[test]
+    fn empty_histogram_normalizes_to_zero() {
+        assert_eq!(Frequencies::new().normalized(1 << 15), [0; 256]);
+    }
+
+    #[test]
+    fn single_byte_takes_everything() {
+        let scaled = assert_normalized(&Frequencies::from_data(b"zzz"), 1 << 15);
+        assert_eq!(scaled[b'z' as usize], 1 << 15);
+    }
+
+    #[test]
+    fn scaled_counts_round_trip() {
+        let scaled = Frequencies::from_data(b"scaled counts").normalized(1 << 11);
+        let mut out = vec![0xaa];
+        write_scaled(&scaled, &mut out);
+        assert_eq!(out.len(), 1 + 32 + 2 * 11);
+        assert_eq!(read_scaled(&out, 1).unwrap(), (scaled, out.len()));
+        assert!(read_scaled(&out[..out.len() - 1], 1).is_err());
+        assert!(read_scaled(&out[..20], 1).is_err());
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let scaled = Frequencies::from_data(b"ab").normalized(1 << 11);
+        let header = write_header(2, &scaled);
+        assert_eq!(read_header(&header, 1 << 11, 2).unwrap(), Some((2, scaled, header.len())));
+        assert_eq!(read_header(&write_header(0, &[0; 256]), 1 << 11, 0).unwrap(), None);
+        for len in [0, 7, 8, 39, header.len() - 1] {
+            assert!(read_header(&header[..len], 1 << 11, 2).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn headers_are_checked_against_the_total_and_limit() {
+        let scaled = Frequencies::from_data(b"ab").normalized(1 << 11);
+        let mut header = write_header(1000, &scaled);
+        assert_eq!(read_header(&header, 1 << 11, 999).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(read_header(&header, 1 << 11, 1000).is_ok());
+        assert!(read_header(&header, 1 << 15, 1000).is_err());
+        // Both counts follow the length and the 32 byte presence map
+        let count = u16::from_le_bytes([header[40], header[41]]);
+        for bad in [count - 1, count + 1, u16::MAX] {
+            header[40..42].copy_from_slice(&bad.to_le_bytes());
+            assert!(read_header(&header, 1 << 11, 1000).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn measures_entropy() {
+        assert_eq!(Frequencies::new().entropy(), 0.0);
+        assert_eq!(Frequencies::from_data(b"aaaa").entropy(), 0.0);
+        assert!((Frequencies::from_data(b"aabb").entropy() - 1.0).abs() < 1e-12);
+    }
+}
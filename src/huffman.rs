@@ -1,15 +1,22 @@
 use core::fmt;
-use std::{collections::HashMap, rc::Rc, cell::RefCell, io::{Write, Read, Seek}};
+use std::{collections::HashMap, rc::Rc, cell::RefCell, io::{self, Write, Read}};
 
 use crate::bintree::{*, self};
+use crate::bitio::{self, BitReader, BitWriter};
+use crate::compiled::{self, CodeTable, CompiledModel};
+use crate::entropy::Backend;
+use crate::flat::{Slot, FILE_MAGIC, FILE_VERSION, MODEL_MAGIC, MODEL_VERSION, MODE_HUFFMAN, MODE_STORED, MODE_RLE, LEAF, HAS_LEFT, HAS_RIGHT, MAX_TREE_NODES};
+use crate::frequency::Frequencies;
+use crate::transform::{Pipeline, Rle, Transform};
 
-#[derive(PartialEq, Eq, PartialOrd)]
+#[derive(PartialEq, Eq)]
 enum Node {
     Leaf(HuffmanNode),
     Branch(Rc<RefCell<BinTree<HuffmanNode>>>)
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct HuffmanNode{
     freq: u64,
     character: Option<u8>
@@ -18,10 +25,21 @@ struct HuffmanNode{
 pub struct HuffmanEncoding {
     bits: Rc<RefCell<Vec<bool>>>
 }
+// A Huffman code on its own: the tree used to decode and the bits each
+// byte is encoded as.
+pub struct HuffmanModel {
+    decoding: Rc<RefCell<BinTree<HuffmanNode>>>,
+    // The code of every byte, plus an escape announcing a byte outside the
+    // model, written raw after it
+    codes: CodeTable,
+}
 pub struct HuffmanState{
     raw_data: Vec<u8>,
-    decoding: Rc<RefCell<BinTree<HuffmanNode>>>,
-    encoding: HashMap<u8, HuffmanEncoding>
+    model: HuffmanModel,
+    // Transforms applied to raw_data before it is Huffman coded, and the
+    // number of bytes they produce
+    pipeline: Pipeline,
+    len: usize
 }
 
 
@@ -40,8 +58,8 @@ impl HuffmanNode {
 
 impl fmt::Display for HuffmanNode {
     fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result { 
-        if self.character.is_some(){
-            write!(f, "({}, {})", self.freq, self.character.unwrap() as char)
+        if let Some(c) = self.character {
+            write!(f, "({}, {})", self.freq, c as char)
         } else {
             write!(f, "({}, None)", self.freq)
         }
@@ -53,6 +71,11 @@ impl Ord for HuffmanNode {
         self.freq.cmp(&self2.freq)
     }
 }
+impl PartialOrd for HuffmanNode {
+    fn partial_cmp(&self, self2: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(self2))
+    }
+}
 
 impl Node {
     fn get_freq(&self) -> u64 {
@@ -69,50 +92,15 @@ impl Node {
     fn add_to_tree(&self, tree: Rc<RefCell<BinTree<HuffmanNode>>>, side: bintree::Side){
         match self{
             Node::Leaf(leaf) => {
-                bintree::add_element(tree, leaf.clone(), side);
+                bintree::add_element(tree, *leaf, side);
             }
             Node::Branch(branch) => {
                 bintree::add_tree(tree, branch.clone(), side);
             }
         }
     }
-    
-    fn update_encoding(&self, encoding: &mut HashMap<u8, HuffmanEncoding>, flag: bool){
-        match self {
-            Node::Leaf(leaf) => {
-                if leaf.character.is_some() {
-                    update_char_encoding(leaf.character.unwrap(), encoding, flag);                
-                }
-            }
-            Node::Branch(branch) => {
-                update_tree_encoding(branch.clone(), encoding, flag);
-            }
-        }
-    }
-} 
-
-fn update_char_encoding(ch: u8, encoding: &mut HashMap<u8, HuffmanEncoding>, flag: bool){
-    if !encoding.contains_key(&ch) {
-        encoding.insert(ch, HuffmanEncoding{bits: Rc::new(RefCell::new(vec!(flag)))});
-    } else {
-        let v = encoding.get(&ch).unwrap();
-        v.bits.borrow_mut().insert(0, flag);
-        encoding.insert(ch, HuffmanEncoding{bits: v.bits.clone()});
-    }
-}
 
-fn update_tree_encoding(tree: Rc<RefCell<BinTree<HuffmanNode>>>, encoding: &mut HashMap<u8, HuffmanEncoding>, flag: bool){
-    if tree.borrow_mut().val.character.is_some() {
-        update_char_encoding(tree.borrow_mut().val.character.unwrap(), encoding, flag);
-        return;
-    }
-    if tree.borrow_mut().left.is_some(){
-       update_tree_encoding(tree.borrow_mut().left.as_ref().unwrap().clone(), encoding, flag); 
-    }
-    if tree.borrow_mut().right.is_some(){
-       update_tree_encoding(tree.borrow_mut().right.as_ref().unwrap().clone(), encoding, flag); 
-    }
-}
+} 
 
 impl Ord for Node {
     fn cmp(&self, self2: &Self) -> std::cmp::Ordering {
@@ -121,9 +109,22 @@ impl Ord for Node {
         freq1.cmp(&freq2)
     }
 }
+impl PartialOrd for Node {
+    fn partial_cmp(&self, self2: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(self2))
+    }
+}
 
-fn generate_tree(mut list: Vec<Node>) -> (Rc<RefCell<BinTree<HuffmanNode>>>, HashMap<u8, HuffmanEncoding>) {
-    let mut encoding = HashMap::<u8, HuffmanEncoding>::new();
+fn generate_tree(mut list: Vec<Node>) -> Rc<RefCell<BinTree<HuffmanNode>>> {
+    if list.len() <= 1 {
+        // No data leaves a bare root; a single symbol hangs off the left of
+        // the root so it still gets a one bit code
+        let tree = BinTree::as_ref(HuffmanNode::empty(list.first().map_or(0, |only| only.get_freq())));
+        if let Some(only) = list.pop() {
+            only.add_to_tree(tree.clone(), Side::Left);
+        }
+        list.push(Node::Branch(tree));
+    }
     while list.len() > 1 {
         // Pop 2 values to add to tree structure
         let val1 = list.remove(0);
@@ -132,278 +133,889 @@ fn generate_tree(mut list: Vec<Node>) -> (Rc<RefCell<BinTree<HuffmanNode>>>, Has
 
         let tree = BinTree::as_ref(HuffmanNode::empty(val1.get_freq() + val2.get_freq()));
         val1.add_to_tree(tree.clone(), Side::Right);
-        val1.update_encoding(&mut encoding, true);
         val2.add_to_tree(tree.clone(), Side::Left);
-        val2.update_encoding(&mut encoding, false);
 
         list.push(Node::Branch(tree.clone()));
         list.sort();
     }
-    match list.get(0).unwrap() {
-        Node::Leaf(_) => {}
-        Node::Branch(b) => {b.borrow_mut().print_tree()}
-    }
-    match list.get(0) {
-        Option::None => {
-            panic!("Generate Tree failed, panicking")
-        }
-        Option::Some(x) => {
-            match x {
-                Node::Leaf(_) => {
-                    panic!("Generate Tree failed, panicking")
-                }
-                Node::Branch(b) => {
-                    (b.clone(), encoding)
-                }
+    match list.pop() {
+        Some(Node::Branch(b)) => b,
+        _ => unreachable!("the list always ends with a single branch")
+    }
+}
+
+fn create_encoding_from_decoding(decoding: Rc<RefCell<BinTree<HuffmanNode>>>) -> (HashMap<u8, HuffmanEncoding>, Option<HuffmanEncoding>) {
+    let mut encoding = HashMap::<u8, HuffmanEncoding>::new();
+    let mut escape = None;
+    for (node, depth) in bintree::preorder(decoding) {
+        let curr_node = node.borrow();
+        if depth == 0 || curr_node.left.is_some() || curr_node.right.is_some() {
+            continue;
+        }
+        // A leaf's code is the way down to it, right meaning 1
+        let bits = bintree::path_to_root(node.clone()).into_iter().map(|side| side == Side::Right).collect();
+        let code = HuffmanEncoding{bits: Rc::new(RefCell::new(bits))};
+        match curr_node.val.character {
+            Some(c) => {
+                encoding.insert(c, code);
             }
+            None => escape = Some(code),
         }
     }
+    (encoding, escape)
 }
 
-fn create_encoding_from_decoding(decoding: Rc<RefCell<BinTree<HuffmanNode>>>) -> HashMap<u8, HuffmanEncoding> {
-    let mut encoding = HashMap::<u8, HuffmanEncoding>::new();
-    let mut stack = Vec::<(Rc<RefCell<BinTree<HuffmanNode>>>, Vec::<bool>)>::new();
-    let mut curr_encoding = Vec::<bool>::new();    
-    let mut curr_node = decoding.clone();
-    while !stack.is_empty() || curr_node.borrow_mut().left.is_some() {
-        if curr_node.borrow_mut().val.character.is_some() {
-            encoding.insert(curr_node.borrow_mut().val.character.unwrap(),
-                HuffmanEncoding{bits: Rc::new(RefCell::new(curr_encoding.clone()))});
-        }
-        if curr_node.borrow_mut().left.is_some() {
-            if curr_node.borrow_mut().right.is_some(){
-                let mut clone = curr_encoding.clone();
-                clone.push(true);
-                stack.push((
-                    curr_node.borrow_mut().right.as_ref().unwrap().clone(),
-                    clone
-                ));
+fn code_table(encoding: &HashMap<u8, HuffmanEncoding>, escape: Option<&HuffmanEncoding>) -> CodeTable {
+    let mut codes = CodeTable::new();
+    for (&c, code) in encoding {
+        codes.insert(c as usize, &code.bits.borrow());
+    }
+    if let Some(escape) = escape {
+        codes.insert(compiled::ESCAPE, &escape.bits.borrow());
+    }
+    codes
+}
+
+impl HuffmanModel {
+    pub fn from_data(data: &[u8]) -> Self {
+        Self::from_frequencies(&Frequencies::from_data(data))
+    }
+
+    pub fn from_frequencies(freqs: &Frequencies) -> Self {
+        Self::build(freqs, false)
+    }
+
+    // A model that can also code bytes missing from `freqs`, through an
+    // escape code weighted as if it had been seen once.
+    pub fn with_escape(freqs: &Frequencies) -> Self {
+        Self::build(freqs, true)
+    }
+
+    pub fn has_escape(&self) -> bool {
+        self.codes.len(compiled::ESCAPE) > 0
+    }
+
+    fn build(freqs: &Frequencies, escape: bool) -> Self {
+        let mut list = Vec::<Node>::new(); 
+        for c in freqs.symbols() {
+            list.push(Node::Leaf(HuffmanNode::new(freqs.count(c), c)));
+        }
+        if escape {
+            list.push(Node::Leaf(HuffmanNode::empty(1)));
+        }
+        list.sort();
+
+        let decoding = generate_tree(list);
+        let (encoding, escape) = create_encoding_from_decoding(decoding.clone());
+        let codes = code_table(&encoding, escape.as_ref());
+        Self { decoding, codes }
+    }
+
+    // Flattens the tree into a `CompiledModel` that threads can share.
+    // Nodes are numbered in the order they are reached, the root first.
+    pub fn compile(&self) -> CompiledModel {
+        let mut nodes = vec![[Slot::Missing; 2]];
+        let mut stack = vec![(self.decoding.clone(), 0)];
+        while let Some((tree, index)) = stack.pop() {
+            let tree = tree.borrow();
+            for (side, child) in [&tree.left, &tree.right].into_iter().enumerate() {
+                let Some(child) = child else { continue };
+                let (val, is_leaf) = {
+                    let node = child.borrow();
+                    (node.val, node.left.is_none() && node.right.is_none())
+                };
+                nodes[index][side] = match val.character {
+                    Some(c) => Slot::Byte(c),
+                    None if is_leaf => Slot::Escape,
+                    None => {
+                        nodes.push([Slot::Missing; 2]);
+                        stack.push((child.clone(), nodes.len() - 1));
+                        Slot::Node((nodes.len() - 1) as u16)
+                    }
+                };
             }
-            let right = curr_node.borrow_mut().right.as_ref().unwrap().clone();
-            curr_node = right;
-            curr_encoding.push(false);
+        }
+        CompiledModel::from_nodes(nodes)
+    }
+
+    // Magic and format version, then the tree as `write_tree` lays it out
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MODEL_MAGIC.to_vec();
+        bytes.push(MODEL_VERSION);
+        self.write_tree(&mut bytes).unwrap();
+        bytes
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < 5 || &bytes[..4] != MODEL_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a huffman model"));
+        }
+        if bytes[4] != MODEL_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported huffman model version"));
+        }
+        bytes = &bytes[5..];
+        Self::read_tree(&mut bytes)
+    }
+
+    // The node count, then every node in preorder as a flag byte (LEAF,
+    // HAS_LEFT, HAS_RIGHT), its frequency as a varint and, for leaves, the
+    // byte itself.
+    fn write_tree<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        bitio::write_varint(&mut bytes, bintree::get_size(self.decoding.clone()));
+        for (curr_node, _) in bintree::preorder(self.decoding.clone()) {
+            write_node(&mut bytes, &curr_node.borrow());
+        }
+        out.write_all(&bytes)
+    }
+
+    fn read_tree<R: Read>(input: &mut R) -> io::Result<Self> {
+        let count = bitio::read_varint_from(input)?;
+        if count == 0 || count > MAX_TREE_NODES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad huffman tree size"));
+        }
+        let (root, flags) = read_node(input)?;
+        // A leaf at the root would leave its byte with an empty code
+        if flags & LEAF != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "huffman tree root is a leaf"));
+        }
+        read_children(input, &root, flags, count)?;
+        let (encoding, escape) = create_encoding_from_decoding(root.clone());
+        let codes = code_table(&encoding, escape.as_ref());
+        Ok(Self { decoding: root, codes })
+    }
+}
+
+// Reads the `count - 1` nodes below `root` in preorder and attaches them.
+fn read_children<R: Read>(input: &mut R, root: &Rc<RefCell<BinTree<HuffmanNode>>>, flags: u8, count: u64) -> io::Result<()> {
+    // Nodes still waiting on children, and whether each side is owed
+    let mut pending = vec![(root.clone(), flags & HAS_LEFT != 0, flags & HAS_RIGHT != 0)];
+    for _ in 1..count {
+        let (node, flags) = read_node(input)?;
+        let parent = pending.last_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "huffman tree has extra nodes"))?;
+        if parent.1 {
+            parent.1 = false;
+            add_tree(parent.0.clone(), node.clone(), Side::Left);
         } else {
-            // There is something in the stack
-            (curr_node, curr_encoding) = stack.pop().unwrap();
+            parent.2 = false;
+            add_tree(parent.0.clone(), node.clone(), Side::Right);
+        }
+        if !parent.1 && !parent.2 {
+            pending.pop();
         }
+        if flags & (HAS_LEFT | HAS_RIGHT) != 0 {
+            pending.push((node, flags & HAS_LEFT != 0, flags & HAS_RIGHT != 0));
+        }
+    }
+    if pending.iter().any(|(_, left, right)| *left || *right) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "huffman tree is missing nodes"));
     }
-    return encoding;
+    Ok(())
 }
 
-impl HuffmanState{
-    pub fn new(raw_data: Vec<u8>) -> Self {
-        let mut map = HashMap::<u8, HuffmanNode>::new();
-        for c in &raw_data {
-            if !map.contains_key(&c) {
-                map.insert(*c, HuffmanNode::new(1, *c));
-            } else {
-                let node = map.get(c).unwrap();
-                map.insert(*c, HuffmanNode::new(node.freq + 1, node.character.unwrap()));
+// With the `serde` feature a code is stored as a string of '0' and '1', and
+// a model as its tree together with the code table derived from it. The
+// table is only there for readers: loading rebuilds it from the tree and
+// refuses a table that disagrees.
+#[cfg(feature = "serde")]
+impl serde::Serialize for HuffmanEncoding {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let bits: String = self.bits.borrow().iter().map(|&bit| if bit { '1' } else { '0' }).collect();
+        serializer.serialize_str(&bits)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for HuffmanEncoding {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let bits = text
+            .chars()
+            .map(|c| match c {
+                '0' => Ok(false),
+                '1' => Ok(true),
+                _ => Err(serde::de::Error::custom("huffman code holds something other than 0 and 1")),
+            })
+            .collect::<Result<Vec<bool>, _>>()?;
+        Ok(Self { bits: Rc::new(RefCell::new(bits)) })
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ModelRepr {
+    tree: BinTree<HuffmanNode>,
+    codes: std::collections::BTreeMap<u8, HuffmanEncoding>,
+    escape: Option<HuffmanEncoding>,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for HuffmanModel {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (encoding, escape) = create_encoding_from_decoding(self.decoding.clone());
+        ModelRepr { tree: self.decoding.borrow().clone(), codes: encoding.into_iter().collect(), escape }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for HuffmanModel {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let repr = ModelRepr::deserialize(deserializer)?;
+        let decoding = Rc::new(RefCell::new(repr.tree));
+        if bintree::get_size(decoding.clone()) > MAX_TREE_NODES {
+            return Err(D::Error::custom("bad huffman tree size"));
+        }
+        if decoding.borrow().val.character.is_some() {
+            return Err(D::Error::custom("huffman tree root is a leaf"));
+        }
+        for (node, _) in bintree::preorder(decoding.clone()) {
+            let node = node.borrow();
+            if node.val.character.is_some() && (node.left.is_some() || node.right.is_some()) {
+                return Err(D::Error::custom("huffman tree leaf has children"));
             }
         }
-        let mut list = Vec::<Node>::new(); 
-        for (_, node) in map {
-            list.push(Node::Leaf(node));
+        // The tree came in by value, so its top nodes need their parent back
+        for child in [&decoding.borrow().left, &decoding.borrow().right].into_iter().flatten() {
+            child.borrow_mut().parent = Some(Rc::downgrade(&decoding));
         }
-        list.sort();
+        let (encoding, escape) = create_encoding_from_decoding(decoding.clone());
+        let same = |a: &HuffmanEncoding, b: &HuffmanEncoding| *a.bits.borrow() == *b.bits.borrow();
+        let codes_match = encoding.len() == repr.codes.len()
+            && repr.codes.iter().all(|(c, code)| encoding.get(c).is_some_and(|ours| same(ours, code)))
+            && match (&escape, &repr.escape) {
+                (Some(ours), Some(theirs)) => same(ours, theirs),
+                (None, None) => true,
+                _ => false,
+            };
+        if !codes_match {
+            return Err(D::Error::custom("huffman code table does not match the tree"));
+        }
+        let codes = code_table(&encoding, escape.as_ref());
+        Ok(Self { decoding, codes })
+    }
+}
 
-        for l in &list {
-            match l {
-                Node::Leaf(leaf) => {println!("{}", leaf)}
-                Node::Branch(_) => {}
-            }
+// What `encode_with` does with a byte the model has no code for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Unseen {
+    // Write the model's escape code followed by the raw byte
+    Escape,
+    // Fail with `ErrorKind::InvalidInput`
+    Reject,
+}
+
+pub fn encode(model: &HuffmanModel, data: &[u8]) -> io::Result<Vec<u8>> {
+    encode_with(model, data, Unseen::Escape)
+}
+
+pub fn encode_with(model: &HuffmanModel, data: &[u8], unseen: Unseen) -> io::Result<Vec<u8>> {
+    model.codes.encode(data, unseen)
+}
+
+// Decodes `len` bytes from `bits`, ignoring the padding after them.
+pub fn decode(model: &HuffmanModel, bits: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let ended = || io::Error::new(io::ErrorKind::UnexpectedEof, "huffman data ended early");
+    let mut reader = BitReader::new(bits);
+    let mut current_node = model.decoding.clone();
+    let mut uncompressed: Vec<u8> = Vec::new();
+    while uncompressed.len() < len {
+        let flag = reader.read_bit().ok_or_else(ended)?;
+        let next = if flag {
+            current_node.borrow().right.clone()
+        } else {
+            current_node.borrow().left.clone()
+        };
+        current_node = next.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad huffman code"))?;
+        let node = current_node.borrow().val;
+        if let Some(c) = node.character {
+            uncompressed.push(c);
+        } else if current_node.borrow().left.is_none() && current_node.borrow().right.is_none() {
+            // Only the escape leaf has neither a byte nor children
+            uncompressed.push(reader.read_bits(8).ok_or_else(ended)? as u8);
+        } else {
+            continue;
         }
+        current_node = model.decoding.clone();
+    }
+    Ok(uncompressed)
+}
 
-        let (root, encoding) = generate_tree(list);
-        
+impl HuffmanState{
+    pub fn new(raw_data: Vec<u8>) -> Self {
+        Self::with_pipeline(raw_data, Pipeline::new())
+    }
+
+    pub fn with_pipeline(raw_data: Vec<u8>, pipeline: Pipeline) -> Self {
+        let transformed = pipeline.forward(&raw_data);
+        let model = HuffmanModel::from_data(&transformed);
+        Self { raw_data, model, pipeline, len: transformed.len() }
+    }
 
-        Self { raw_data: raw_data, decoding: root, encoding}
+    pub fn raw_data(&self) -> &[u8] {
+        &self.raw_data
+    }
 
+    pub fn model(&self) -> &HuffmanModel {
+        &self.model
     }
 
     pub fn compress(&self) -> Vec<u8> {
-        // First pass, slow and inefficient
-        let mut raw_flags = Vec::<u8>::new();
-        for c in &self.raw_data{
-            let encoding_option = self.encoding.get(c);
-            if encoding_option.is_none(){
-                continue;
-            }
-            let encoding = encoding_option.unwrap();
-            for flag in encoding.bits.borrow_mut().clone() {
-                raw_flags.push(if flag {1} else {0});
+        encode_with(&self.model, &self.pipeline.forward(&self.raw_data), Unseen::Reject)
+            .expect("model is built from the data it codes")
+    }
+    pub fn decompress(&self, compressed : Vec<u8>) -> io::Result<Vec<u8>> {
+        let uncompressed = decode(&self.model, &compressed, self.len)?;
+        self.pipeline.inverse(&uncompressed)
+    }
+    pub fn save_to_file(&self, mut file: &std::fs::File){
+        self.save(&mut file).unwrap();
+    }
+
+    // Writes what `load` reads: magic and version, the pipeline header, the
+    // mode, the length and then the tree and payload, or the data stored or
+    // run-length coded where that comes out smaller.
+    pub fn save<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let transformed = self.pipeline.forward(&self.raw_data);
+        let mut tree = Vec::new();
+        self.model.write_tree(&mut tree)?;
+        let payload = encode_with(&self.model, &transformed, Unseen::Reject)
+            .expect("model is built from the data it codes");
+        let (mode, _) = file_mode(&transformed, tree.len() + payload.len());
+        out.write_all(FILE_MAGIC)?;
+        out.write_all(&[FILE_VERSION])?;
+        self.pipeline.write_header(out)?;
+        out.write_all(&[mode])?;
+        out.write_all(&(self.len as u64).to_le_bytes())?;
+        match mode {
+            MODE_HUFFMAN => {
+                out.write_all(&tree)?;
+                out.write_all(&payload)
             }
+            MODE_STORED => out.write_all(&transformed),
+            _ => out.write_all(&Rle.forward(&transformed)),
         }
-        let mut compressed_data = Vec::<u8>::new();
-        let mut bit = 0;
-        let mut current = 0;
-        compressed_data.push(0);
-        for flag in raw_flags {
-            compressed_data[current] |= flag << bit;
-            bit += 1;
-            if bit == 8 {
-                bit = 0;
-                current += 1;
-                compressed_data.push(0);
-            }
+    }
+
+    pub fn load_from_file(file: &mut std::fs::File) -> io::Result<(Self, Vec<u8>)> {
+        Self::load(file, usize::MAX)
+    }
+
+    // Reads a file written by `save_to_file`, returning the state and the
+    // payload as stored. No buffer grows past `max_len` bytes, so untrusted
+    // input should come with a limit. Files stored without a tree get a
+    // model built from their data.
+    pub fn load<R: Read>(input: &mut R, max_len: usize) -> io::Result<(Self, Vec<u8>)> {
+        let mut magic = [0u8; 5];
+        input.read_exact(&mut magic)?;
+        if &magic[..4] != FILE_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a huffman file"));
+        }
+        if magic[4] != FILE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported huffman file version"));
+        }
+        let pipeline = Pipeline::read_header(input)?;
+        let mut mode = [0u8; 1];
+        input.read_exact(&mut mode)?;
+        let len = read_u64(input)?;
+        if len > max_len as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "huffman file too large"));
         }
+        let len = len as usize;
+        let tree = match mode[0] {
+            MODE_HUFFMAN => Some(HuffmanModel::read_tree(input)?),
+            MODE_STORED | MODE_RLE => None,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown huffman file mode")),
+        };
+        let mut raw_data_u8 = Vec::<u8>::new();
+        input.read_to_end(&mut raw_data_u8)?;
+        let uncompressed = match &tree {
+            Some(model) => decode(model, &raw_data_u8, len)?,
+            None if mode[0] == MODE_STORED => raw_data_u8.clone(),
+            None => Rle.inverse(&raw_data_u8, len)?,
+        };
+        if uncompressed.len() != len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "huffman file length mismatch"));
+        }
+        let model = tree.unwrap_or_else(|| HuffmanModel::from_data(&uncompressed));
+        let raw_data = pipeline.inverse_with_limit(&uncompressed, max_len)?;
+        let hfmn = Self{
+            raw_data,
+            model,
+            pipeline,
+            len
+        };
+        Ok((hfmn, raw_data_u8))
+    }
+}
+fn write_node(out: &mut Vec<u8>, node: &BinTree<HuffmanNode>) {
+    let mut flags = 0;
+    if node.val.character.is_some() {
+        flags |= LEAF;
+    }
+    if node.left.is_some() {
+        flags |= HAS_LEFT;
+    }
+    if node.right.is_some() {
+        flags |= HAS_RIGHT;
+    }
+    out.push(flags);
+    bitio::write_varint(out, node.val.freq);
+    if let Some(c) = node.val.character {
+        out.push(c);
+    }
+}
+fn read_node<R: Read>(input: &mut R) -> io::Result<(Rc<RefCell<BinTree<HuffmanNode>>>, u8)> {
+    let mut flags = [0u8; 1];
+    input.read_exact(&mut flags)?;
+    let flags = flags[0];
+    if flags & !(LEAF | HAS_LEFT | HAS_RIGHT) != 0 || (flags & LEAF != 0 && flags != LEAF) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad huffman tree node"));
+    }
+    let freq = bitio::read_varint_from(input)?;
+    let node = if flags & LEAF != 0 {
+        let mut c = [0u8; 1];
+        input.read_exact(&mut c)?;
+        HuffmanNode::new(freq, c[0])
+    } else {
+        HuffmanNode::empty(freq)
+    };
+    Ok((BinTree::as_ref(node), flags))
+}
 
-        return compressed_data;
+// How `save` stores data whose Huffman tree and payload take `huffman_len`
+// bytes, and the size that comes to: Huffman coded unless storing it or RLE
+// alone is smaller, estimated as `entropy::cheapest` does for its blocks,
+// with ties going to Huffman.
+pub(crate) fn file_mode(transformed: &[u8], huffman_len: usize) -> (u8, usize) {
+    let stored = Backend::Stored.estimate(transformed);
+    let rle = Backend::Rle.estimate(transformed);
+    [(MODE_HUFFMAN, huffman_len), (MODE_STORED, stored), (MODE_RLE, rle)]
+        .into_iter()
+        .min_by_key(|&(_, cost)| cost)
+        .unwrap()
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut integer_u8 = [0u8; 8];
+    input.read_exact(&mut integer_u8)?;
+    Ok(u64::from_le_bytes(integer_u8))
+}
+
+// Code length of every symbol given its frequency, built with the same
+// repeated merge of the two rarest nodes as `generate_tree`. Symbols with a
+// frequency of zero get no code. If the tree comes out deeper than
+// `max_len` the frequencies are flattened and the tree rebuilt. Panics
+// unless `max_len` bits are enough for a code per symbol, that is at most
+// `2^max_len` symbols with a non-zero frequency and `max_len` at least 1.
+pub fn code_lengths(freqs: &[u64], max_len: u32) -> Vec<u32> {
+    let symbols = freqs.iter().filter(|&&f| f > 0).count() as u64;
+    assert!(
+        symbols == 0 || (max_len >= 1 && symbols <= 1u64 << max_len.min(63)),
+        "{} symbols do not fit in {} bit codes",
+        symbols,
+        max_len
+    );
+    let mut weights = freqs.to_vec();
+    loop {
+        let lengths = unbounded_code_lengths(&weights);
+        if lengths.iter().all(|&len| len <= max_len) {
+            return lengths;
+        }
+        for weight in weights.iter_mut().filter(|w| **w > 0) {
+            *weight = (*weight >> 1) + 1;
+        }
     }
-    pub fn decompress(&self, compressed : Vec<u8>) -> Vec<u8> {
-        let mut bit = 0;
-        let mut current_node = self.decoding.clone();
-        let mut uncompressed: Vec<u8> = Vec::new();
-        for c in compressed {
-            while bit < 8 {
-                let flag = c & (1<<bit);
-                if flag != 0 {
-                    if current_node.borrow_mut().right.is_some() {
-                        let temp = current_node.borrow_mut().right.as_ref().unwrap().clone();
-                        current_node = temp;
-                    }
-                } else {
-                    if current_node.borrow_mut().left.is_some() {
-                        let temp = current_node.borrow_mut().left.as_ref().unwrap().clone();
-                        current_node = temp;
-                    }
-                }
-                if current_node.borrow_mut().val.character.is_some() {
-                    uncompressed.push(current_node.borrow_mut().val.character.unwrap());
-                    current_node = self.decoding.clone();
-                }
-                bit += 1;
+}
+
+fn unbounded_code_lengths(weights: &[u64]) -> Vec<u32> {
+    let mut lengths = vec![0u32; weights.len()];
+    let mut list: Vec<(u64, Vec<usize>)> = weights.iter()
+        .enumerate()
+        .filter(|(_, &w)| w > 0)
+        .map(|(symbol, &w)| (w, vec![symbol]))
+        .collect();
+    if list.len() == 1 {
+        lengths[list[0].1[0]] = 1;
+        return lengths;
+    }
+    list.sort_by_key(|(w, _)| *w);
+    while list.len() > 1 {
+        let (w1, mut s1) = list.remove(0);
+        let (w2, s2) = list.remove(0);
+        s1.extend(s2);
+        for &symbol in &s1 {
+            lengths[symbol] += 1;
+        }
+        let at = list.partition_point(|(w, _)| *w <= w1 + w2);
+        list.insert(at, (w1 + w2, s1));
+    }
+    lengths
+}
+
+// Assigns canonical codes: shorter codes first, ties broken by symbol.
+pub fn canonical_codes(lengths: &[u32]) -> Vec<u32> {
+    let mut symbols: Vec<usize> = (0..lengths.len()).filter(|&s| lengths[s] > 0).collect();
+    symbols.sort_by_key(|&s| (lengths[s], s));
+    let mut codes = vec![0u32; lengths.len()];
+    let mut code = 0u32;
+    let mut prev_len = 0;
+    for s in symbols {
+        code <<= lengths[s] - prev_len;
+        codes[s] = code;
+        code += 1;
+        prev_len = lengths[s];
+    }
+    codes
+}
+
+pub struct CanonicalDecoder {
+    // counts[len] is the number of codes of that length
+    counts: Vec<u32>,
+    symbols: Vec<usize>,
+}
+
+impl CanonicalDecoder {
+    pub fn new(lengths: &[u32]) -> Self {
+        let max_len = lengths.iter().copied().max().unwrap_or(0) as usize;
+        let mut counts = vec![0u32; max_len + 1];
+        for &len in lengths.iter().filter(|&&len| len > 0) {
+            counts[len as usize] += 1;
+        }
+        let mut symbols: Vec<usize> = (0..lengths.len()).filter(|&s| lengths[s] > 0).collect();
+        symbols.sort_by_key(|&s| (lengths[s], s));
+        Self { counts, symbols }
+    }
+
+    pub fn decode(&self, reader: &mut BitReader) -> Option<usize> {
+        let mut code = 0u32;
+        let mut first = 0u32;
+        let mut index = 0u32;
+        for &count in &self.counts[1..] {
+            code |= u32::from(reader.read_bit()?);
+            if code < first + count {
+                return Some(self.symbols[(index + code - first) as usize]);
             }
-            bit = 0;
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
         }
-        return uncompressed;
+        None
     }
-    pub fn save_to_file(&self, mut file: &std::fs::File){
-        // Find lowest left node
-        let mut curr_node: Rc<RefCell<BinTree<HuffmanNode>>> = self.decoding.clone();
-        let mut stack = Vec::<Rc<RefCell<BinTree<HuffmanNode>>>>::new();
-
-        while curr_node.borrow_mut().left.is_some(){
-            stack.push(curr_node.clone());
-            let left = curr_node.borrow_mut().left.as_ref().unwrap().clone();
-            curr_node = left; 
-        }
-        let offset = (5 * bintree::get_size(self.decoding.clone())) + 8;
-        
-        let mut offset_u8 = Vec::<u8>::new();
-        for i in 0..8 {
-            offset_u8.push(((offset >> (8*i)) & 0xFF) as u8);
-        }
-        file.write(offset_u8.as_slice());
-        
-        write_node(file, curr_node.clone());
-
-        while bintree::is_next_in_order(curr_node.clone(), stack.is_empty()) {
-            if curr_node.borrow_mut().right.is_some() {
-                let right = curr_node.borrow_mut().right.as_ref().unwrap().clone();
-                curr_node = right;
-                while curr_node.borrow_mut().left.is_some(){
-                    stack.push(curr_node.clone());
-                    let left = curr_node.borrow_mut().left.as_ref().unwrap().clone();
-                    curr_node = left; 
-                }
+}
+
+// Lengths are delta coded: a 5 bit starting length, then for every symbol
+// a series of 1x steps (10 = up, 11 = down) terminated by a 0.
+pub fn write_code_lengths(writer: &mut BitWriter, lengths: &[u32]) {
+    let mut current = lengths.first().copied().unwrap_or(0);
+    writer.push_bits(current as u64, 5);
+    for &len in lengths {
+        while current != len {
+            writer.push_bit(true);
+            writer.push_bit(current > len);
+            if current > len {
+                current -= 1;
             } else {
-                curr_node = stack.pop().unwrap().clone();
+                current += 1;
             }
-            // Write current node
-            write_node(file, curr_node.clone());
-        }
-        curr_node = self.decoding.clone();
-        // not needed 
-        stack.clear();
-        
-        if curr_node.borrow_mut().right.is_some(){
-            stack.push(curr_node.borrow_mut().right.as_ref().unwrap().clone());
-        }
-        
-        while !stack.is_empty() || curr_node.borrow_mut().left.is_some() {
-            write_node(file, curr_node.clone());
-            if curr_node.borrow_mut().left.is_some() {
-                if curr_node.borrow_mut().right.is_some() {
-                    stack.push(curr_node.borrow_mut().right.as_ref().unwrap().clone());
-                }
-                let left = curr_node.borrow_mut().left.as_ref().unwrap().clone();
-                curr_node = left;
+        }
+        writer.push_bit(false);
+    }
+}
+
+pub fn read_code_lengths(reader: &mut BitReader, count: usize, max_len: u32) -> io::Result<Vec<u32>> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "truncated code lengths");
+    let mut current = reader.read_bits(5).ok_or_else(truncated)? as u32;
+    let mut lengths = Vec::with_capacity(count);
+    for _ in 0..count {
+        while reader.read_bit().ok_or_else(truncated)? {
+            if reader.read_bit().ok_or_else(truncated)? {
+                current = current.wrapping_sub(1);
             } else {
-                curr_node = stack.pop().unwrap();
+                current += 1;
+            }
+            if current > max_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad code length"));
+            }
+        }
+        if current > max_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad code length"));
+        }
+        lengths.push(current);
+    }
+    // Over-subscribed lengths would give several symbols the same code
+    let kraft: u64 = lengths.iter().filter(|&&len| len > 0).map(|&len| 1u64 << (max_len - len)).sum();
+    if kraft > 1 << max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "over-subscribed code lengths"));
+    }
+    Ok(lengths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_round_trip(name: &str, data: &[u8]) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("hfmn-{}-{}.hf", name, std::process::id()));
+        HuffmanState::new(data.to_vec()).save_to_file(&std::fs::File::create(&path).unwrap());
+        let (loaded, _) = HuffmanState::load_from_file(&mut std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        loaded.raw_data().to_vec()
+    }
+
+    #[test]
+    fn empty_input_round_trips() {
+        let hfmn = HuffmanState::new(Vec::new());
+        let compressed = hfmn.compress();
+        assert!(compressed.is_empty());
+        assert!(hfmn.decompress(compressed).unwrap().is_empty());
+        assert!(file_round_trip("empty", &[]).is_empty());
+    }
+
+    #[test]
+    fn single_symbol_uses_one_bit_codes() {
+        let data = vec![b'a'; 20];
+        let hfmn = HuffmanState::new(data.clone());
+        let compressed = hfmn.compress();
+        assert_eq!(compressed.len(), 3);
+        assert_eq!(hfmn.decompress(compressed).unwrap(), data);
+        assert_eq!(file_round_trip("single", &data), data);
+    }
+
+    #[test]
+    fn single_symbol_model_survives_serialization() {
+        let model = HuffmanModel::from_bytes(&HuffmanModel::from_data(b"zzz").to_bytes()).unwrap();
+        let bits = encode(&model, b"zzzzz").unwrap();
+        assert_eq!(decode(&model, &bits, 5).unwrap(), b"zzzzz");
+        assert!(encode(&model, b"y").is_err());
+    }
+
+    #[test]
+    fn binary_data_round_trips_through_file() {
+        // NUL bytes, every byte value and many internal nodes of equal weight
+        let mut data: Vec<u8> = (0..=255).collect();
+        data.extend(std::iter::repeat_n(0, 300));
+        assert_eq!(file_round_trip("binary", &data), data);
+    }
+
+    #[test]
+    fn large_frequencies_are_not_truncated() {
+        let mut bytes = Vec::new();
+        write_node(&mut bytes, &BinTree::new(HuffmanNode::new(5 << 32, 0)));
+        let (node, flags) = read_node(&mut bytes.as_slice()).unwrap();
+        assert_eq!(flags, LEAF);
+        assert_eq!(node.borrow().val.freq, 5 << 32);
+        assert_eq!(node.borrow().val.character, Some(0));
+    }
+
+    #[test]
+    fn codes_are_prefix_free() {
+        let model = HuffmanModel::from_data(b"abracadabra alakazam");
+        let (encoding, _) = create_encoding_from_decoding(model.decoding.clone());
+        let codes: Vec<Vec<bool>> = encoding.values().map(|e| e.bits.borrow().clone()).collect();
+        for (i, a) in codes.iter().enumerate() {
+            for (j, b) in codes.iter().enumerate() {
+                assert!(i == j || !b.starts_with(a), "{:?} prefixes {:?}", a, b);
             }
         }
-        write_node(file, curr_node.clone());
-        // save compressed data
-        let data = self.compress();
-        file.write(data.as_slice());
     }
 
-    pub fn load_from_file(mut file: &mut std::fs::File) -> (Self, Vec<u8>){
-        let mut inorder = Vec::<HuffmanNode>::new();
-        let mut preorder = Vec::<HuffmanNode>::new();
-        let offset = read_u64(&mut file);
-        println!("{}", Seek::seek(&mut file, std::io::SeekFrom::Current(0)).unwrap());
-        while Seek::seek(&mut file, std::io::SeekFrom::Current(0)).unwrap() < offset {
-            inorder.push(read_node(&mut file));
+    #[test]
+    fn frequent_symbols_get_shorter_codes() {
+        let model = HuffmanModel::from_data(b"aaaaaaaaaaaaaaaabbbbbbbbccccdde");
+        let len = |c: u8| model.codes.len(c as usize);
+        assert!(len(b'a') <= len(b'b'));
+        assert!(len(b'b') <= len(b'c'));
+        assert!(len(b'c') <= len(b'e'));
+    }
+
+    #[test]
+    fn model_bytes_round_trip() {
+        let model = HuffmanModel::from_data(b"mississippi river");
+        let loaded = HuffmanModel::from_bytes(&model.to_bytes()).unwrap();
+        let bits = encode(&model, b"sip").unwrap();
+        assert_eq!(decode(&loaded, &bits, 3).unwrap(), b"sip");
+        assert_eq!(loaded.to_bytes(), model.to_bytes());
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let path = std::env::temp_dir().join(format!("hfmn-malformed-{}.hf", std::process::id()));
+        HuffmanState::new(b"hello hello".to_vec()).save_to_file(&std::fs::File::create(&path).unwrap());
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(HuffmanState::load(&mut file.as_slice(), usize::MAX).unwrap().0.raw_data(), b"hello hello");
+        for end in 0..file.len() - 1 {
+            assert!(HuffmanState::load(&mut &file[..end], usize::MAX).is_err(), "truncated at {}", end);
         }
-        // 2*size of  tree + offset = (2*(size of tree + offset)) - offset  
-        while Seek::seek(&mut file, std::io::SeekFrom::Current(0)).unwrap() < (2*offset)-8 {
-            preorder.push(read_node(&mut file));
+        assert!(HuffmanState::load(&mut file.as_slice(), 4).is_err());
+        let mut bad_magic = file.clone();
+        bad_magic[0] = b'X';
+        assert!(HuffmanState::load(&mut bad_magic.as_slice(), usize::MAX).is_err());
+    }
+
+    fn saved(data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        HuffmanState::new(data.to_vec()).save(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn loaded(bytes: &[u8]) -> Vec<u8> {
+        HuffmanState::load(&mut &bytes[..], usize::MAX).unwrap().0.raw_data().to_vec()
+    }
+
+    // Magic, version, empty pipeline header, mode and length
+    const HEADER_LEN: usize = 15;
+
+    #[test]
+    fn incompressible_data_is_stored() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let noise: Vec<u8> = (0..5000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        for data in [noise.clone(), noise[..1].to_vec(), (0..=255).collect(), Vec::new()] {
+            let bytes = saved(&data);
+            assert!(bytes.len() <= data.len() + HEADER_LEN, "{} bytes saved as {}", data.len(), bytes.len());
+            assert_eq!(loaded(&bytes), data);
         }
-        let tree = create_from_orders(inorder, preorder);
-        tree.borrow_mut().print_tree();
-        let encoding = create_encoding_from_decoding(tree.clone());
-        let mut raw_data_u8 = Vec::<u8>::new();
-        file.read_to_end(&mut raw_data_u8);
-        let mut hfmn = Self{
-            raw_data: Vec::new(),
-            encoding: encoding,
-            decoding: tree
-        };
-        let raw_data = hfmn.decompress(raw_data_u8.to_vec());
-        println!("Size of decompressed data: {}", raw_data.len());
-        hfmn.raw_data = raw_data.clone();
-        return (hfmn, raw_data_u8);
-    }
-}
-fn write_node(mut file: &std::fs::File, curr_node: Rc<RefCell<BinTree<HuffmanNode>>>) {
-    let mut freq = Vec::<u8>::new();
-    let mut char = Vec::<u8>::new();
-    for i in 0..4 {
-        freq.push(((curr_node.borrow_mut().val.freq >> (8*i)) & 0xFF) as u8);
-    }
-    char.push(if curr_node.borrow_mut().val.character.is_some() {
-            *curr_node.borrow_mut().val.character.as_ref().unwrap()
-        } else {0});
-    file.write(freq.as_slice());
-    file.write(char.as_slice());
-
-}
-fn read_u64(file: &mut std::fs::File) -> u64 {
-    let mut integer_u8 = vec![0u8; 8];
-    let result = file.read_exact(&mut integer_u8);
-    let mut integer: u64 = 0;
-    assert!(result.is_ok());
-    for i in 0..8{
-        integer |= (integer_u8[i] as u64) << (i*8);
-    }
-    integer
-}
-fn read_node(file: &mut std::fs::File) -> HuffmanNode {
-    let mut freq_u8 = vec![0u8; 4];
-    let mut result = file.read_exact(&mut freq_u8);
-    let mut freq: u32 = 0;
-    assert!(result.is_ok());
-    for i in 0..4{
-        freq |= (freq_u8[i] as u32) << (i*8);
-    }
-    let mut char_u8 = vec![0u8; 1];
-    result = file.read_exact(&mut char_u8);
-    assert!(result.is_ok());
-    if char_u8[0] == 0 {
-        HuffmanNode::empty(freq as u64)
-    } else {
-        HuffmanNode::new(freq as u64, char_u8[0])
+        assert_eq!(saved(&noise)[6], MODE_STORED);
+    }
+
+    #[test]
+    fn runs_are_saved_run_length_coded() {
+        let data: Vec<u8> = (0..=255u8).flat_map(|c| [c; 100]).collect();
+        let bytes = saved(&data);
+        assert_eq!(bytes[6], MODE_RLE);
+        assert_eq!(bytes.len(), HEADER_LEN + Rle::encoded_len(&data));
+        let (state, payload) = HuffmanState::load(&mut bytes.as_slice(), usize::MAX).unwrap();
+        assert_eq!(state.raw_data(), data.as_slice());
+        assert_eq!(payload, Rle.forward(&data));
+        // The rebuilt model still codes the data
+        assert_eq!(state.decompress(state.compress()).unwrap(), data);
+        assert!(HuffmanState::load(&mut bytes.as_slice(), data.len() - 1).is_err());
+    }
+
+    #[test]
+    fn text_stays_huffman_coded() {
+        let bytes = saved(&b"hello hello, plain text".repeat(20));
+        assert_eq!(bytes[6], MODE_HUFFMAN);
+    }
+
+    #[test]
+    fn bad_file_modes_are_errors() {
+        let mut bytes = saved(&[1, 2, 3, 4, 5]);
+        assert_eq!(bytes[6], MODE_STORED);
+        // Stored data has to match the length exactly
+        assert!(HuffmanState::load(&mut &bytes[..bytes.len() - 1], usize::MAX).is_err());
+        bytes.push(6);
+        assert!(HuffmanState::load(&mut bytes.as_slice(), usize::MAX).is_err());
+        bytes.pop();
+        bytes[6] = 3;
+        assert!(HuffmanState::load(&mut bytes.as_slice(), usize::MAX).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn malformed_trees_are_errors() {
+        let leaf_root = [1, LEAF, 3, b'a'];
+        assert!(HuffmanModel::read_tree(&mut &leaf_root[..]).is_err());
+        let too_many = [3, HAS_LEFT, 1, LEAF, 1, b'a', LEAF, 1, b'b'];
+        assert!(HuffmanModel::read_tree(&mut &too_many[..]).is_err());
+        let too_few = [2, HAS_LEFT | HAS_RIGHT, 2, LEAF, 1, b'a'];
+        assert!(HuffmanModel::read_tree(&mut &too_few[..]).is_err());
+        let leaf_with_child = [2, HAS_LEFT, 1, LEAF | HAS_LEFT, 1, b'a'];
+        assert!(HuffmanModel::read_tree(&mut &leaf_with_child[..]).is_err());
+        let huge = [0xff, 0xff, 0xff, 0xff, 0x0f];
+        assert!(HuffmanModel::read_tree(&mut &huge[..]).is_err());
+    }
+
+    #[test]
+    fn code_lengths_round_trip() {
+        let lengths = code_lengths(&[50, 20, 20, 5, 0, 1, 1, 3], 5);
+        let mut writer = BitWriter::new();
+        write_code_lengths(&mut writer, &lengths);
+        let bits = writer.finish();
+        assert_eq!(read_code_lengths(&mut BitReader::new(&bits), lengths.len(), 5).unwrap(), lengths);
+    }
+
+    #[test]
+    fn code_lengths_fill_the_limit_exactly() {
+        let lengths = code_lengths(&[1; 256], 8);
+        assert!(lengths.iter().all(|&len| len == 8));
+        assert_eq!(code_lengths(&[0, 7, 0], 1), [0, 1, 0]);
+        assert_eq!(code_lengths(&[0; 4], 0), [0; 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "do not fit")]
+    fn code_lengths_reject_too_many_symbols() {
+        code_lengths(&[1; 300], 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "do not fit")]
+    fn code_lengths_reject_a_zero_limit() {
+        code_lengths(&[5], 0);
+    }
+
+    #[test]
+    fn over_subscribed_code_lengths_are_errors() {
+        // Three one-bit codes can't all be distinct
+        let mut writer = BitWriter::new();
+        write_code_lengths(&mut writer, &[1, 1, 1]);
+        let bits = writer.finish();
+        assert!(read_code_lengths(&mut BitReader::new(&bits), 3, 24).is_err());
+        // A code left unused is fine
+        let mut writer = BitWriter::new();
+        write_code_lengths(&mut writer, &[1, 2, 0]);
+        let bits = writer.finish();
+        assert_eq!(read_code_lengths(&mut BitReader::new(&bits), 3, 24).unwrap(), [1, 2, 0]);
+    }
+
+    #[test]
+    fn models_free_their_trees() {
+        let model = HuffmanModel::from_data(b"abracadabra");
+        let root = Rc::downgrade(&model.decoding);
+        let leaf = bintree::preorder(model.decoding.clone())
+            .find(|(node, _)| node.borrow().val.character.is_some())
+            .map(|(node, _)| Rc::downgrade(&node))
+            .unwrap();
+        drop(model);
+        assert!(root.upgrade().is_none());
+        assert!(leaf.upgrade().is_none());
+    }
+
+    #[test]
+    fn escape_only_model_codes_any_byte() {
+        let model = HuffmanModel::with_escape(&Frequencies::new());
+        let bits = encode(&model, b"any").unwrap();
+        assert_eq!(decode(&model, &bits, 3).unwrap(), b"any");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trips_models_and_histograms() {
+        let data = b"serde keeps the tree and its code table".to_vec();
+        let freqs = Frequencies::from_data(&data);
+        let json = serde_json::to_string(&freqs).unwrap();
+        assert_eq!(serde_json::from_str::<Frequencies>(&json).unwrap(), freqs);
+        assert!(serde_json::from_str::<Frequencies>("[1,2,3]").is_err());
+
+        let model = HuffmanModel::with_escape(&freqs);
+        let json = serde_json::to_value(&model).unwrap();
+        assert_eq!(json["codes"].as_object().unwrap().len(), freqs.symbols().count());
+        let back: HuffmanModel = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(back.to_bytes(), model.to_bytes());
+        assert_eq!(encode(&back, b"unseen").unwrap(), encode(&model, b"unseen").unwrap());
+
+        let mut tampered = json;
+        tampered["codes"]["115"] = serde_json::Value::from("0");
+        assert!(serde_json::from_value::<HuffmanModel>(tampered).is_err());
+    }
+}
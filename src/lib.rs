@@ -0,0 +1,34 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod analysis;
+#[cfg(feature = "std")]
+pub mod ans;
+#[cfg(feature = "std")]
+pub mod bench;
+#[cfg(feature = "std")]
+pub mod bintree;
+pub mod bitio;
+#[cfg(feature = "std")]
+pub mod bwt;
+#[cfg(feature = "std")]
+pub mod bzip;
+#[cfg(feature = "std")]
+pub mod compiled;
+#[cfg(feature = "std")]
+pub mod dictionary;
+#[cfg(feature = "std")]
+pub mod entropy;
+pub mod flat;
+#[cfg(feature = "std")]
+pub mod frequency;
+#[cfg(feature = "std")]
+pub mod huffman;
+#[cfg(feature = "std")]
+pub mod mtf;
+#[cfg(feature = "std")]
+pub mod range;
+#[cfg(feature = "std")]
+pub mod transform;
@@ -1,9 +1,87 @@
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Read};
 
-mod huffman;
-mod bintree;
+use huffmancodes::analysis;
+use huffmancodes::bench::{self, Report};
+use huffmancodes::{bintree, bzip, huffman};
+
+const USAGE: &str = "usage: hfmn [bench [-n ITERATIONS] [FILE...] | stats [FILE...]]";
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => demo(),
+        Some("bench") => run_bench(&args[1..]),
+        Some("stats") => run_stats(&args[1..]),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+// Benchmarks the given files, or the built-in corpus when there are none
+fn run_bench(args: &[String]) {
+    let mut iterations = 5;
+    let mut samples = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-n" {
+            match args.next().and_then(|n| n.parse().ok()).filter(|&n| n > 0) {
+                Some(n) => iterations = n,
+                None => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                }
+            }
+            continue;
+        }
+        match std::fs::read(arg) {
+            Ok(data) => samples.push((arg.clone(), data)),
+            Err(e) => {
+                eprintln!("{}: {}", arg, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if samples.is_empty() {
+        samples = bench::corpus().into_iter().map(|(name, data)| (name.to_string(), data)).collect();
+    }
+    println!("{}", Report::header());
+    for (name, data) in &samples {
+        for report in bench::run_all(name, data, iterations) {
+            println!("{}", report);
+        }
+    }
+}
+
+// Code statistics for the given files, or for stdin when there are none
+fn run_stats(args: &[String]) {
+    if args.is_empty() {
+        let mut data = Vec::new();
+        if let Err(e) = io::stdin().read_to_end(&mut data) {
+            eprintln!("stdin: {}", e);
+            std::process::exit(1);
+        }
+        println!("{}", analysis::analyze(&data));
+        return;
+    }
+    for (i, arg) in args.iter().enumerate() {
+        let data = match std::fs::read(arg) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("{}: {}", arg, e);
+                std::process::exit(1);
+            }
+        };
+        if i > 0 {
+            println!();
+        }
+        println!("{}:", arg);
+        println!("{}", analysis::analyze(&data));
+    }
+}
+
+fn demo() {
     let root  = bintree::BinTree::as_ref(0);
     println!("Test 1: ########");
     root.borrow().print_tree();
@@ -23,16 +101,20 @@ fn main() {
     for line in stdin.lock().lines() {
         let line_str = line.unwrap_or("".to_string()) + "\n";
         for c in line_str.as_bytes() {
-            data.push(c.clone());
+            data.push(*c);
         }
     }
 
     println!("size in: {}", data.len());
 
+    let bzip_data = bzip::compress(&data);
+    println!("Size of bzip-style data: {}", bzip_data.len());
+    println!("bzip-style round trip: {}", bzip::decompress(&bzip_data, data.len()).is_ok_and(|d| d == data));
+
     let hfmn = huffman::HuffmanState::new(data);
     let compressed_data = hfmn.compress();
     println!("Size of compressed data: {}", compressed_data.len());
-    let decompressed_data = hfmn.decompress(compressed_data.clone());
+    let decompressed_data = hfmn.decompress(compressed_data.clone()).unwrap();
     for u in &decompressed_data {
         print!("{}", *u as char);
     }
@@ -43,10 +125,10 @@ fn main() {
     }
     {
         let file = std::fs::File::open("hello.txt");
-        let (hfmn2, raw_data) = huffman::HuffmanState::load_from_file(&mut file.unwrap());
-        // for u in raw_data {
-        //     print!("{}", u as char);
-        // }
-        println!("{} == {}: {}", raw_data.len(), compressed_data.len(), raw_data == compressed_data);
+        let (hfmn2, payload) = huffman::HuffmanState::load_from_file(&mut file.unwrap()).unwrap();
+        // The file holds the data stored or run-length coded when Huffman
+        // coding would not make it smaller
+        println!("Size of saved payload: {}", payload.len());
+        println!("file round trip: {}", hfmn2.raw_data() == decompressed_data.as_slice());
     }
 }
@@ -0,0 +1,140 @@
+use std::io;
+
+// Symbols produced by `encode_zero_runs`. Runs of zeros are written as a
+// bijective base-2 number using RUN_A (1) and RUN_B (2) as digits, every
+// other MTF index v becomes v + 1, and the block is closed by END_OF_BLOCK.
+pub const RUN_A: u16 = 0;
+pub const RUN_B: u16 = 1;
+pub const END_OF_BLOCK: u16 = 257;
+pub const ALPHABET_SIZE: usize = 258;
+
+pub fn move_to_front(data: &[u8]) -> Vec<u8> {
+    let mut order: Vec<u8> = (0..=255).collect();
+    let mut out = Vec::with_capacity(data.len());
+    for &c in data {
+        let index = order.iter().position(|&o| o == c).unwrap();
+        order.remove(index);
+        order.insert(0, c);
+        out.push(index as u8);
+    }
+    out
+}
+
+pub fn move_to_front_inverse(indices: &[u8]) -> Vec<u8> {
+    let mut order: Vec<u8> = (0..=255).collect();
+    let mut out = Vec::with_capacity(indices.len());
+    for &index in indices {
+        let c = order.remove(index as usize);
+        order.insert(0, c);
+        out.push(c);
+    }
+    out
+}
+
+pub fn encode_zero_runs(indices: &[u8]) -> Vec<u16> {
+    let mut symbols = Vec::new();
+    let mut run = 0u64;
+    for &index in indices {
+        if index == 0 {
+            run += 1;
+            continue;
+        }
+        push_run(&mut symbols, run);
+        run = 0;
+        symbols.push(index as u16 + 1);
+    }
+    push_run(&mut symbols, run);
+    symbols.push(END_OF_BLOCK);
+    symbols
+}
+
+// Expands the symbols up to END_OF_BLOCK, refusing to produce more than
+// `max_len` indices.
+pub fn decode_zero_runs(symbols: &[u16], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut indices = Vec::new();
+    let mut run = 0usize;
+    let mut digit = 1usize;
+    for &symbol in symbols {
+        if symbol == RUN_A || symbol == RUN_B {
+            let weight = if symbol == RUN_A { 1 } else { 2 };
+            run = digit
+                .checked_mul(weight)
+                .and_then(|d| run.checked_add(d))
+                .filter(|&r| r <= max_len - indices.len())
+                .ok_or_else(|| invalid("zero run longer than block"))?;
+            digit = digit.saturating_mul(2);
+            continue;
+        }
+        indices.resize(indices.len() + run, 0);
+        run = 0;
+        digit = 1;
+        if symbol == END_OF_BLOCK {
+            return Ok(indices);
+        }
+        if symbol > END_OF_BLOCK || indices.len() == max_len {
+            return Err(invalid("bad symbol in zero run coding"));
+        }
+        indices.push((symbol - 1) as u8);
+    }
+    Err(invalid("missing end of block"))
+}
+
+fn push_run(symbols: &mut Vec<u16>, mut run: u64) {
+    while run > 0 {
+        if run & 1 == 1 {
+            symbols.push(RUN_A);
+            run = (run - 1) / 2;
+        } else {
+            symbols.push(RUN_B);
+            run = (run - 2) / 2;
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn move_to_front_round_trips() {
+        let data = b"bananaaa\0\xff\xffzz".to_vec();
+        let indices = move_to_front(&data);
+        assert_eq!(indices[..4], [b'b', b'b', b'n', 1]);
+        assert_eq!(move_to_front_inverse(&indices), data);
+    }
+
+    #[test]
+    fn zero_runs_round_trip() {
+        // Run lengths 1 and 2 are a single digit, 3..=6 need two and mix
+        // RUN_A with RUN_B
+        for run in 0..70 {
+            let mut indices = vec![0; run];
+            indices.extend([5, 0, 0, 0, 255]);
+            indices.extend(vec![0; run]);
+            let symbols = encode_zero_runs(&indices);
+            assert_eq!(symbols.last(), Some(&END_OF_BLOCK));
+            assert_eq!(decode_zero_runs(&symbols, indices.len()).unwrap(), indices);
+        }
+    }
+
+    #[test]
+    fn runs_use_both_digits() {
+        assert_eq!(encode_zero_runs(&[0; 3]), [RUN_A, RUN_A, END_OF_BLOCK]);
+        assert_eq!(encode_zero_runs(&[0; 4]), [RUN_B, RUN_A, END_OF_BLOCK]);
+        assert_eq!(encode_zero_runs(&[0; 5]), [RUN_A, RUN_B, END_OF_BLOCK]);
+        assert_eq!(decode_zero_runs(&[RUN_B, RUN_B, RUN_A, END_OF_BLOCK], 10).unwrap(), [0; 10]);
+    }
+
+    #[test]
+    fn decoding_respects_the_limit() {
+        let symbols = encode_zero_runs(&[0; 10]);
+        assert!(decode_zero_runs(&symbols, 9).is_err());
+        assert!(decode_zero_runs(&[RUN_B; 80], usize::MAX).is_err());
+        assert!(decode_zero_runs(&[3, 3], 10).is_err());
+        assert!(decode_zero_runs(&[END_OF_BLOCK + 1], 10).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::io;
+
+use crate::frequency::{self, Frequencies};
+
+// Probabilities are scaled to 15 bits, leaving at least 9 bits of
+// precision in the 32 bit range after division.
+pub const PROB_BITS: u32 = 15;
+const TOP: u32 = 1 << 24;
+
+// Carry-propagating range coder over a static model, in the style of LZMA:
+// bytes are held back in `cache` until it is known that no carry out of
+// `low` can change them.
+pub struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+pub struct RangeDecoder<'a> {
+    code: u32,
+    range: u32,
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        Self { low: 0, range: u32::MAX, cache: 0, cache_size: 1, out: Vec::new() }
+    }
+}
+
+impl RangeEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn encode(&mut self, cum: u32, freq: u32, total: u32) {
+        let r = self.range / total;
+        self.low += r as u64 * cum as u64;
+        self.range = r * freq;
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut pending = self.cache;
+            while self.cache_size > 0 {
+                self.out.push(pending.wrapping_add(carry));
+                pending = 0xFF;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+}
+
+impl<'a> RangeDecoder<'a> {
+    pub fn new(input: &'a [u8]) -> io::Result<Self> {
+        if input.len() < 5 {
+            return Err(invalid("truncated range coded data"));
+        }
+        let code = input[1..5].iter().fold(0u32, |code, &b| (code << 8) | b as u32);
+        Ok(Self { code, range: u32::MAX, input, pos: 5 })
+    }
+    // The scaled value the next symbol's interval must contain
+    pub fn target(&mut self, total: u32) -> u32 {
+        self.range /= total;
+        (self.code / self.range).min(total - 1)
+    }
+    // Consumes the symbol found with `target`
+    pub fn consume(&mut self, cum: u32, freq: u32) -> io::Result<()> {
+        self.code -= self.range * cum;
+        self.range *= freq;
+        while self.range < TOP {
+            let b = *self.input.get(self.pos).ok_or_else(|| invalid("truncated range coded data"))?;
+            self.pos += 1;
+            self.code = (self.code << 8) | b as u32;
+            self.range <<= 8;
+        }
+        Ok(())
+    }
+}
+
+// Payload layout: the header written by `frequency::write_header`, then
+// the coded data.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let scaled = Frequencies::from_data(data).normalized(1 << PROB_BITS);
+    let mut out = frequency::write_header(data.len(), &scaled);
+    let cum = cumulative(&scaled);
+    let mut encoder = RangeEncoder::new();
+    for &c in data {
+        encoder.encode(cum[c as usize], scaled[c as usize], 1 << PROB_BITS);
+    }
+    out.extend(encoder.finish());
+    out
+}
+
+pub fn decode(payload: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let Some((len, scaled, pos)) = frequency::read_header(payload, 1 << PROB_BITS, max_len)? else {
+        return Ok(Vec::new());
+    };
+    let cum = cumulative(&scaled);
+    let mut decoder = RangeDecoder::new(&payload[pos..])?;
+    let mut out = Vec::with_capacity(len);
+    for _ in 0..len {
+        let target = decoder.target(1 << PROB_BITS);
+        let c = cum.partition_point(|&start| start <= target) - 1;
+        decoder.consume(cum[c], scaled[c])?;
+        out.push(c as u8);
+    }
+    Ok(out)
+}
+
+fn cumulative(scaled: &[u32; 256]) -> [u32; 257] {
+    let mut cum = [0u32; 257];
+    for c in 0..256 {
+        cum[c + 1] = cum[c] + scaled[c];
+    }
+    cum
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
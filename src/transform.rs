@@ -0,0 +1,577 @@
+use std::io::{self, Read, Write};
+
+use crate::bitio::{BitReader, BitWriter};
+use crate::frequency::Frequencies;
+use crate::huffman::{self, CanonicalDecoder};
+use crate::{bwt, mtf};
+
+// A reversible stage over a byte buffer. `id` and `params` are what gets
+// written to the file header so the stage can be rebuilt by
+// `Pipeline::read_header`. `inverse` fails rather than produce more than
+// `max_len` bytes, checking as it goes so hostile input can't make it
+// allocate first.
+pub trait Transform {
+    fn id(&self) -> u8;
+    fn params(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    fn forward(&self, data: &[u8]) -> Vec<u8>;
+    fn inverse(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>>;
+}
+
+const RLE_ID: u8 = 1;
+const DELTA_ID: u8 = 2;
+const MTF_ID: u8 = 3;
+const BWT_ID: u8 = 4;
+const HUFFMAN_ID: u8 = 5;
+const BYTE_SPLIT_ID: u8 = 6;
+
+// Stages run in the order they were added; inverse runs them backwards.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Transform>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn then<T: Transform + 'static>(mut self, stage: T) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+    pub fn forward(&self, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for stage in &self.stages {
+            data = stage.forward(&data);
+        }
+        data
+    }
+    pub fn inverse(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.inverse_with_limit(data, usize::MAX)
+    }
+    // Fails as soon as a stage would produce more than `max_len` bytes, so
+    // a header stacking expanding stages cannot blow up memory.
+    pub fn inverse_with_limit(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut data = data.to_vec();
+        for stage in self.stages.iter().rev() {
+            data = stage.inverse(&data, max_len)?;
+            // Stages from outside this module may not check for themselves
+            check_len(data.len(), max_len)?;
+        }
+        Ok(data)
+    }
+
+    // Stage count, then an id, parameter length and parameters per stage.
+    pub fn write_header<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let count = u8::try_from(self.stages.len()).map_err(|_| invalid_input("more than 255 transforms"))?;
+        out.write_all(&[count])?;
+        for stage in &self.stages {
+            let params = stage.params();
+            let params_len = u8::try_from(params.len()).map_err(|_| invalid_input("transform parameters too long"))?;
+            out.write_all(&[stage.id(), params_len])?;
+            out.write_all(&params)?;
+        }
+        Ok(())
+    }
+    pub fn read_header<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut count = [0u8; 1];
+        input.read_exact(&mut count)?;
+        let mut pipeline = Self::new();
+        for _ in 0..count[0] {
+            let mut tag = [0u8; 2];
+            input.read_exact(&mut tag)?;
+            let mut params = vec![0u8; tag[1] as usize];
+            input.read_exact(&mut params)?;
+            pipeline.stages.push(stage_from_header(tag[0], &params)?);
+        }
+        Ok(pipeline)
+    }
+}
+
+fn stage_from_header(id: u8, params: &[u8]) -> io::Result<Box<dyn Transform>> {
+    match id {
+        RLE_ID => Ok(Box::new(Rle)),
+        DELTA_ID => Ok(Box::new(Delta { stride: read_width(params, "bad delta parameters")? })),
+        MTF_ID => Ok(Box::new(MoveToFront)),
+        BWT_ID => Ok(Box::new(Bwt { block_size: read_width(params, "bad bwt parameters")? })),
+        HUFFMAN_ID => Ok(Box::new(Huffman)),
+        BYTE_SPLIT_ID => Ok(Box::new(ByteSplit { width: read_width(params, "bad byte split parameters")? })),
+        _ => Err(invalid("unknown transform")),
+    }
+}
+
+// Parameters holding a single non-zero u32 size
+fn read_width(params: &[u8], message: &str) -> io::Result<usize> {
+    let bytes: [u8; 4] = params.try_into().map_err(|_| invalid(message))?;
+    match u32::from_le_bytes(bytes) {
+        0 => Err(invalid(message)),
+        width => Ok(width as usize),
+    }
+}
+
+// The encoding side of `read_width`: sizes are written to the header as a
+// u32 and zero is never valid.
+fn check_width(width: usize, message: &str) -> io::Result<usize> {
+    if width == 0 || width > u32::MAX as usize {
+        return Err(invalid_input(message));
+    }
+    Ok(width)
+}
+
+// Run-length coding in the style of bzip2's first stage: after four equal
+// bytes a count of further repeats (0-251) follows.
+pub struct Rle;
+
+const RLE_MAX_RUN: usize = 4 + 251;
+
+impl Rle {
+    // Length of `forward(data)`, without building it
+    pub fn encoded_len(data: &[u8]) -> usize {
+        let mut len = 0;
+        let mut i = 0;
+        while i < data.len() {
+            let run = data[i..].iter().take(RLE_MAX_RUN).take_while(|&&d| d == data[i]).count();
+            len += if run >= 4 { 5 } else { run };
+            i += run;
+        }
+        len
+    }
+}
+
+impl Transform for Rle {
+    fn id(&self) -> u8 {
+        RLE_ID
+    }
+    fn forward(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut i = 0;
+        while i < data.len() {
+            let c = data[i];
+            let run = data[i..].iter().take(RLE_MAX_RUN).take_while(|&&d| d == c).count();
+            if run >= 4 {
+                out.extend_from_slice(&[c; 4]);
+                out.push((run - 4) as u8);
+            } else {
+                out.extend(std::iter::repeat_n(c, run));
+            }
+            i += run;
+        }
+        out
+    }
+    fn inverse(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len().min(max_len));
+        let mut i = 0;
+        while i < data.len() {
+            let c = data[i];
+            let mut run = data[i..].iter().take(4).take_while(|&&d| d == c).count();
+            i += run;
+            if run == 4 {
+                run += *data.get(i).ok_or_else(|| invalid("truncated run"))? as usize;
+                i += 1;
+            }
+            check_len(out.len() + run, max_len)?;
+            out.extend(std::iter::repeat_n(c, run));
+        }
+        Ok(out)
+    }
+}
+
+// Replaces every byte with its difference from the byte `stride` positions
+// earlier. With the stride set to the sample width, each byte of an array
+// of little endian samples is diffed against the same byte of the previous
+// sample.
+pub struct Delta {
+    stride: usize,
+}
+
+impl Delta {
+    pub fn new(stride: usize) -> io::Result<Self> {
+        Ok(Self { stride: check_width(stride, "delta stride must be between 1 and u32::MAX")? })
+    }
+}
+
+impl Default for Delta {
+    fn default() -> Self {
+        Self { stride: 1 }
+    }
+}
+
+impl Transform for Delta {
+    fn id(&self) -> u8 {
+        DELTA_ID
+    }
+    fn params(&self) -> Vec<u8> {
+        (self.stride as u32).to_le_bytes().to_vec()
+    }
+    fn forward(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = data.to_vec();
+        for i in (self.stride..data.len()).rev() {
+            out[i] = data[i].wrapping_sub(data[i - self.stride]);
+        }
+        out
+    }
+    fn inverse(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        check_len(data.len(), max_len)?;
+        let mut out = data.to_vec();
+        for i in self.stride..out.len() {
+            out[i] = out[i].wrapping_add(out[i - self.stride]);
+        }
+        Ok(out)
+    }
+}
+
+// Splits an array of `width` byte samples into byte planes: first byte 0 of
+// every sample, then byte 1 and so on. Bytes past the last whole sample are
+// left at the end untouched.
+pub struct ByteSplit {
+    width: usize,
+}
+
+impl ByteSplit {
+    pub fn new(width: usize) -> io::Result<Self> {
+        Ok(Self { width: check_width(width, "byte split width must be between 1 and u32::MAX")? })
+    }
+}
+
+impl Transform for ByteSplit {
+    fn id(&self) -> u8 {
+        BYTE_SPLIT_ID
+    }
+    fn params(&self) -> Vec<u8> {
+        (self.width as u32).to_le_bytes().to_vec()
+    }
+    fn forward(&self, data: &[u8]) -> Vec<u8> {
+        let samples = data.len() / self.width;
+        if samples == 0 {
+            return data.to_vec();
+        }
+        let mut out = Vec::with_capacity(data.len());
+        for plane in 0..self.width {
+            out.extend((0..samples).map(|i| data[i * self.width + plane]));
+        }
+        out.extend_from_slice(&data[samples * self.width..]);
+        out
+    }
+    fn inverse(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        check_len(data.len(), max_len)?;
+        let samples = data.len() / self.width;
+        // Also keeps a huge width read from a header from looping over planes
+        if samples == 0 {
+            return Ok(data.to_vec());
+        }
+        let mut out = vec![0u8; data.len()];
+        for plane in 0..self.width {
+            for i in 0..samples {
+                out[i * self.width + plane] = data[plane * samples + i];
+            }
+        }
+        out[samples * self.width..].copy_from_slice(&data[samples * self.width..]);
+        Ok(out)
+    }
+}
+
+pub struct MoveToFront;
+
+impl Transform for MoveToFront {
+    fn id(&self) -> u8 {
+        MTF_ID
+    }
+    fn forward(&self, data: &[u8]) -> Vec<u8> {
+        mtf::move_to_front(data)
+    }
+    fn inverse(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        check_len(data.len(), max_len)?;
+        Ok(mtf::move_to_front_inverse(data))
+    }
+}
+
+// Burrows-Wheeler transform of each block, stored as the primary index
+// (u32, little endian) followed by the last column.
+pub struct Bwt {
+    block_size: usize,
+}
+
+impl Bwt {
+    pub fn new(block_size: usize) -> io::Result<Self> {
+        Ok(Self { block_size: check_width(block_size, "bwt block size must be between 1 and u32::MAX")? })
+    }
+}
+
+impl Default for Bwt {
+    fn default() -> Self {
+        Self { block_size: crate::bzip::BLOCK_SIZE }
+    }
+}
+
+impl Transform for Bwt {
+    fn id(&self) -> u8 {
+        BWT_ID
+    }
+    // `new` bounds the block size, and with it every primary index
+    fn params(&self) -> Vec<u8> {
+        (self.block_size as u32).to_le_bytes().to_vec()
+    }
+    fn forward(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 4);
+        for block in data.chunks(self.block_size) {
+            let (last, primary) = bwt::forward(block);
+            out.extend_from_slice(&(primary as u32).to_le_bytes());
+            out.extend(last);
+        }
+        out
+    }
+    fn inverse(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len().min(max_len));
+        let mut rest = data;
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(invalid("truncated bwt block"));
+            }
+            let primary = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let len = self.block_size.min(rest.len() - 4);
+            check_len(out.len() + len, max_len)?;
+            out.extend(bwt::inverse(&rest[4..4 + len], primary)?);
+            rest = &rest[4 + len..];
+        }
+        Ok(out)
+    }
+}
+
+// Canonical Huffman coding of the buffer: code lengths for all 256 bytes,
+// the symbol count, then the codes.
+pub struct Huffman;
+
+const HUFFMAN_MAX_CODE_LENGTH: u32 = 24;
+
+impl Huffman {
+    // Length of `forward(data)`, worked out from the histogram alone
+    pub fn encoded_len(data: &[u8]) -> usize {
+        let freqs = Frequencies::from_data(data);
+        let lengths = huffman::code_lengths(freqs.counts(), HUFFMAN_MAX_CODE_LENGTH);
+        let mut writer = BitWriter::new();
+        huffman::write_code_lengths(&mut writer, &lengths);
+        let codes: u64 = freqs.symbols().map(|c| freqs.count(c) * lengths[c as usize] as u64).sum();
+        (writer.bit_len() + 64 + codes).div_ceil(8) as usize
+    }
+}
+
+impl Transform for Huffman {
+    fn id(&self) -> u8 {
+        HUFFMAN_ID
+    }
+    fn forward(&self, data: &[u8]) -> Vec<u8> {
+        let freqs = Frequencies::from_data(data);
+        let lengths = huffman::code_lengths(freqs.counts(), HUFFMAN_MAX_CODE_LENGTH);
+        let codes = huffman::canonical_codes(&lengths);
+        let mut writer = BitWriter::new();
+        huffman::write_code_lengths(&mut writer, &lengths);
+        writer.push_bits(data.len() as u64, 64);
+        for &c in data {
+            writer.push_code(codes[c as usize], lengths[c as usize]);
+        }
+        writer.finish()
+    }
+    fn inverse(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut reader = BitReader::new(data);
+        let lengths = huffman::read_code_lengths(&mut reader, 256, HUFFMAN_MAX_CODE_LENGTH)?;
+        let decoder = CanonicalDecoder::new(&lengths);
+        let len = reader.read_bits(64).ok_or_else(|| invalid("truncated huffman stream"))?;
+        // Every symbol takes at least one bit
+        if len > reader.bits_left() as u64 {
+            return Err(invalid("truncated huffman stream"));
+        }
+        check_len(len.try_into().unwrap_or(usize::MAX), max_len)?;
+        let mut out = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let symbol = decoder.decode(&mut reader).ok_or_else(|| invalid("bad huffman code"))?;
+            out.push(symbol as u8);
+        }
+        Ok(out)
+    }
+}
+
+// The limit of `Transform::inverse`
+fn check_len(len: usize, max_len: usize) -> io::Result<()> {
+    if len > max_len {
+        return Err(invalid("transformed data too large"));
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(pipeline: &Pipeline) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        pipeline.write_header(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn pipeline_round_trips_through_its_header() {
+        let data = b"pipelines undo their stages in reverse order, aaaaaaaaaaaa".repeat(20);
+        let pipeline = Pipeline::new().then(Rle).then(Bwt::new(300).unwrap()).then(MoveToFront).then(Huffman);
+        let bytes = header(&pipeline);
+        let read = Pipeline::read_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.len(), 4);
+        assert_eq!(header(&read), bytes);
+        let transformed = pipeline.forward(&data);
+        assert_eq!(read.inverse(&transformed).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_pipeline_is_one_byte() {
+        let pipeline = Pipeline::new();
+        assert_eq!(header(&pipeline), [0]);
+        assert_eq!(pipeline.inverse(b"as is").unwrap(), b"as is");
+    }
+
+    #[test]
+    fn read_header_rejects_bad_headers() {
+        let cases: [(&[u8], io::ErrorKind); 5] = [
+            (&[], io::ErrorKind::UnexpectedEof),
+            (&[1, RLE_ID], io::ErrorKind::UnexpectedEof),
+            (&[1, BWT_ID, 4, 1, 0], io::ErrorKind::UnexpectedEof),
+            (&[1, 99, 0], io::ErrorKind::InvalidData),
+            (&[1, BWT_ID, 4, 0, 0, 0, 0], io::ErrorKind::InvalidData),
+        ];
+        for (bytes, kind) in cases {
+            assert_eq!(Pipeline::read_header(&mut &bytes[..]).err().map(|e| e.kind()), Some(kind), "{:?}", bytes);
+        }
+        // Sizes are exactly four bytes
+        assert!(Pipeline::read_header(&mut &[1, BWT_ID, 2, 1, 0][..]).is_err());
+    }
+
+    #[test]
+    fn write_header_rejects_what_it_cannot_record() {
+        let mut pipeline = Pipeline::new();
+        for _ in 0..256 {
+            pipeline = pipeline.then(Rle);
+        }
+        let err = pipeline.write_header(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        struct Verbose;
+        impl Transform for Verbose {
+            fn id(&self) -> u8 {
+                0
+            }
+            fn params(&self) -> Vec<u8> {
+                vec![0; 256]
+            }
+            fn forward(&self, data: &[u8]) -> Vec<u8> {
+                data.to_vec()
+            }
+            fn inverse(&self, data: &[u8], _max_len: usize) -> io::Result<Vec<u8>> {
+                Ok(data.to_vec())
+            }
+        }
+        let err = Pipeline::new().then(Verbose).write_header(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn bwt_block_size_must_fit_the_header() {
+        assert_eq!(Bwt::new(0).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        #[cfg(target_pointer_width = "64")]
+        assert!(Bwt::new(u32::MAX as usize + 1).is_err());
+        assert!(Bwt::new(u32::MAX as usize).is_ok());
+    }
+
+    fn round_trip<T: Transform>(stage: &T, data: &[u8]) -> Vec<u8> {
+        let transformed = stage.forward(data);
+        assert_eq!(transformed.len(), data.len());
+        assert_eq!(stage.inverse(&transformed, data.len()).unwrap(), data);
+        transformed
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        let samples: Vec<u8> = (0..100u16).flat_map(|i| (1000 + 3 * i).to_le_bytes()).collect();
+        let transformed = round_trip(&Delta::new(2).unwrap(), &samples);
+        assert_eq!(transformed[..4], [samples[0], samples[1], 3, 0]);
+        // A trailing partial sample and a stride longer than the data
+        round_trip(&Delta::new(2).unwrap(), &samples[..31]);
+        round_trip(&Delta::new(500).unwrap(), &samples);
+        round_trip(&Delta::default(), b"");
+    }
+
+    #[test]
+    fn byte_split_round_trips() {
+        let samples: Vec<u8> = (0..10u32).flat_map(|i| (i * 0x0101).to_le_bytes()).collect();
+        let transformed = round_trip(&ByteSplit::new(4).unwrap(), &samples);
+        assert_eq!(transformed[..10], (0..10).collect::<Vec<u8>>()[..]);
+        assert!(transformed[20..].iter().all(|&b| b == 0));
+        // The odd bytes at the end stay where they are
+        let transformed = round_trip(&ByteSplit::new(4).unwrap(), &samples[..7]);
+        assert_eq!(transformed[4..], samples[4..7]);
+        round_trip(&ByteSplit::new(64).unwrap(), &samples);
+        round_trip(&ByteSplit::new(1).unwrap(), &samples);
+    }
+
+    #[test]
+    fn sample_filters_keep_their_size_in_the_header() {
+        let pipeline = Pipeline::new().then(Delta::new(3).unwrap()).then(ByteSplit::new(70_000).unwrap());
+        let bytes = header(&pipeline);
+        assert_eq!(bytes, [2, DELTA_ID, 4, 3, 0, 0, 0, BYTE_SPLIT_ID, 4, 0x70, 0x11, 1, 0]);
+        assert_eq!(header(&Pipeline::read_header(&mut bytes.as_slice()).unwrap()), bytes);
+        // Both sizes are required
+        assert!(Pipeline::read_header(&mut &[1, DELTA_ID, 0][..]).is_err());
+        assert!(Pipeline::read_header(&mut &[1, BYTE_SPLIT_ID, 0][..]).is_err());
+    }
+
+    #[test]
+    fn sample_filters_reject_sizes_they_cannot_write() {
+        for err in [Delta::new(0).err(), ByteSplit::new(0).err()] {
+            assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        }
+        #[cfg(target_pointer_width = "64")]
+        {
+            assert!(Delta::new(u32::MAX as usize + 1).is_err());
+            assert!(ByteSplit::new(u32::MAX as usize + 1).is_err());
+        }
+    }
+
+    #[test]
+    fn stages_stop_at_the_limit() {
+        // Each five bytes expand to 259, so the limit is hit long before
+        // the whole output exists
+        let runs = [7, 7, 7, 7, 255].repeat(1000);
+        assert!(Rle.inverse(&runs, 259 * 1000 - 1).is_err());
+        assert_eq!(Rle.inverse(&runs, 259 * 1000).unwrap().len(), 259 * 1000);
+
+        let coded = Huffman.forward(&[3; 5000]);
+        assert!(Huffman.inverse(&coded, 4999).is_err());
+        let data = b"some bytes".repeat(10);
+        for stage in [&MoveToFront as &dyn Transform, &Delta::default(), &ByteSplit::new(2).unwrap(), &Bwt::new(30).unwrap()] {
+            let transformed = stage.forward(&data);
+            assert!(stage.inverse(&transformed, data.len() - 1).is_err());
+            assert_eq!(stage.inverse(&transformed, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn stacked_transforms_respect_the_limit() {
+        let data = vec![0u8; 10_000];
+        let pipeline = Pipeline::new().then(Rle).then(Rle);
+        let transformed = pipeline.forward(&data);
+        assert!(pipeline.inverse_with_limit(&transformed, 1000).is_err());
+        assert_eq!(pipeline.inverse_with_limit(&transformed, data.len()).unwrap(), data);
+    }
+}
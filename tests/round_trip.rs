@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use huffmancodes::huffman::{self, HuffmanModel, HuffmanState, Unseen};
+use proptest::prelude::*;
+
+// Every file round trip gets its own path so the cases can run in parallel
+fn file_round_trip(data: &[u8]) -> Vec<u8> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "hfmn-prop-{}-{}.hf",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    HuffmanState::new(data.to_vec()).save_to_file(&File::create(&path).unwrap());
+    let (loaded, _) = HuffmanState::load_from_file(&mut File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    loaded.raw_data().to_vec()
+}
+
+fn memory_round_trip(data: &[u8]) -> Vec<u8> {
+    let hfmn = HuffmanState::new(data.to_vec());
+    hfmn.decompress(hfmn.compress()).unwrap()
+}
+
+// Mostly one byte with a sprinkling of others, so the tree gets deep and
+// lopsided
+fn skewed() -> impl Strategy<Value = Vec<u8>> {
+    (any::<u8>(), prop::collection::vec((0u8..16, any::<u8>()), 0..2000)).prop_map(|(common, picks)| {
+        picks.into_iter().map(|(roll, other)| if roll == 0 { other } else { common }).collect()
+    })
+}
+
+// All 256 byte values in some order, optionally followed by more data
+fn all_symbols() -> impl Strategy<Value = Vec<u8>> {
+    (Just((0..=255).collect::<Vec<u8>>()).prop_shuffle(), prop::collection::vec(any::<u8>(), 0..500))
+        .prop_map(|(mut data, tail)| {
+            data.extend(tail);
+            data
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn random_bytes_round_trip(data in prop::collection::vec(any::<u8>(), 0..2000)) {
+        prop_assert_eq!(memory_round_trip(&data), data.clone());
+        prop_assert_eq!(file_round_trip(&data), data);
+    }
+
+    #[test]
+    fn skewed_bytes_round_trip(data in skewed()) {
+        prop_assert_eq!(memory_round_trip(&data), data.clone());
+        prop_assert_eq!(file_round_trip(&data), data);
+    }
+
+    #[test]
+    fn all_symbols_round_trip(data in all_symbols()) {
+        prop_assert_eq!(memory_round_trip(&data), data.clone());
+        prop_assert_eq!(file_round_trip(&data), data);
+    }
+
+    #[test]
+    fn few_symbols_round_trip(data in prop::collection::vec(0u8..3, 0..50)) {
+        prop_assert_eq!(memory_round_trip(&data), data.clone());
+        prop_assert_eq!(file_round_trip(&data), data);
+    }
+
+    #[test]
+    fn compiled_model_matches_tree(data in skewed()) {
+        let model = HuffmanModel::from_data(&data);
+        let compiled = model.compile();
+        let bits = compiled.encode(&data, Unseen::Reject).unwrap();
+        prop_assert_eq!(&bits, &huffman::encode(&model, &data).unwrap());
+        prop_assert_eq!(compiled.decode(&bits, data.len()).unwrap(), data);
+    }
+}